@@ -0,0 +1,320 @@
+extern crate image;
+extern crate nalgebra;
+extern crate num_cpus;
+extern crate omniverse;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+
+use nalgebra::{Point2, Vector3, Vector4};
+use omniverse::planet;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+const USAGE: &str = "Usage: bake_maps [terrain.yaml] [--output <directory>] \
+                     [--resolution <pixels per face edge>] \
+                     [--equirectangular-width <pixels>] [--seed <seed>] [--radius <radius>] \
+                     [--precision single|double]";
+
+/// Settings read from the command line
+struct Options {
+    terrain: String,
+    output: PathBuf,
+    resolution: usize,
+    equirectangular_width: usize,
+    seed: u32,
+    radius: f64,
+    precision: planet::Precision,
+}
+
+/// The terrain sampled on a grid of pixels in row-major order
+struct Raster {
+    width: usize,
+    height: usize,
+    heights: Vec<f64>,
+    normals: Vec<Vector3<f64>>,
+    colors: Vec<Vector4<f32>>,
+}
+
+/// Describes how the values in the baked images map back to the terrain, written next to the
+/// images.
+#[derive(Serialize)]
+struct Manifest {
+    seed: u32,
+    radius: f64,
+    resolution: usize,
+    equirectangular_width: usize,
+
+    /// The heights that map to 0 and 65535 in the height images
+    height_min: f64,
+    height_max: f64,
+
+    faces: Vec<String>,
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let terrain = std::fs::read_to_string(&options.terrain)
+        .map_err(|err| err.to_string())
+        .and_then(|source| planet::Terrain::from_yaml(&source).map_err(|err| err.to_string()));
+    let terrain = match terrain {
+        Ok(terrain) => terrain,
+        Err(err) => {
+            eprintln!("Could not load '{}': {}", options.terrain, err);
+            std::process::exit(1);
+        }
+    };
+
+    let description = planet::Description {
+        radius: options.radius,
+        seed: options.seed,
+        precision: options.precision,
+        sea_level: None,
+    };
+    let generator = planet::Generator::new(description, terrain, planet::RendererConfig::default());
+
+    // The pixels of a face are centered within the grid so the faces do not overlap
+    let resolution = options.resolution;
+    let faces: Vec<(planet::Face, Raster)> = planet::Face::values()
+        .map(|&face| {
+            let coordinates = (0..resolution * resolution)
+                .map(|i| {
+                    let x = (i % resolution) as f64 + 0.5;
+                    let y = (i / resolution) as f64 + 0.5;
+                    let offset = Point2::new(x / resolution as f64, y / resolution as f64);
+                    planet::lat_lon(&planet::face_direction(face, &offset))
+                })
+                .collect();
+            (
+                face,
+                sample(&generator, coordinates, resolution, resolution),
+            )
+        })
+        .collect();
+
+    // Latitude runs from the +Y pole at the top to the -Y pole at the bottom and longitude from
+    // -180 degrees on the left to 180 degrees on the right
+    let width = options.equirectangular_width;
+    let height = width / 2;
+    let coordinates = (0..width * height)
+        .map(|i| {
+            let x = (i % width) as f64 + 0.5;
+            let y = (i / width) as f64 + 0.5;
+            (
+                0.5 * PI - y / height as f64 * PI,
+                x / width as f64 * 2.0 * PI - PI,
+            )
+        })
+        .collect();
+    let equirectangular = sample(&generator, coordinates, width, height);
+
+    // All images share the same height range so they can be compared with each other
+    let (height_min, height_max) = faces
+        .iter()
+        .map(|(_, raster)| raster)
+        .chain(Some(&equirectangular))
+        .flat_map(|raster| raster.heights.iter())
+        .fold((std::f64::MAX, std::f64::MIN), |(min, max), &height| {
+            (min.min(height), max.max(height))
+        });
+
+    let mut names = Vec::new();
+    let mut result = std::fs::create_dir_all(&options.output).map_err(|err| err.to_string());
+    for (face, raster) in faces.iter() {
+        let name = format!("{:?}", face).to_lowercase();
+        result = result
+            .and_then(|_| write_raster(&options.output, &name, raster, height_min, height_max));
+        names.push(name);
+    }
+    result = result
+        .and_then(|_| {
+            write_raster(
+                &options.output,
+                "equirectangular",
+                &equirectangular,
+                height_min,
+                height_max,
+            )
+        })
+        .and_then(|_| {
+            let manifest = Manifest {
+                seed: options.seed,
+                radius: options.radius,
+                resolution: options.resolution,
+                equirectangular_width: options.equirectangular_width,
+                height_min,
+                height_max,
+                faces: names,
+            };
+            let manifest =
+                serde_json::to_string_pretty(&manifest).map_err(|err| err.to_string())?;
+            std::fs::write(options.output.join("manifest.json"), manifest)
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = result {
+        eprintln!("Could not write to '{}': {}", options.output.display(), err);
+        std::process::exit(1);
+    }
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        terrain: "resources/terrain.yaml".to_string(),
+        output: PathBuf::from("bake"),
+        resolution: 512,
+        equirectangular_width: 0,
+        seed: 0,
+        radius: 400_000.0,
+        precision: planet::Precision::Single,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.terrain = arg;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--output" => options.output = PathBuf::from(&value),
+            "--resolution" => options.resolution = value.parse().map_err(|_| invalid())?,
+            "--equirectangular-width" => {
+                options.equirectangular_width = value.parse().map_err(|_| invalid())?
+            }
+            "--seed" => options.seed = value.parse().map_err(|_| invalid())?,
+            "--radius" => options.radius = value.parse().map_err(|_| invalid())?,
+            "--precision" => options.precision = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    if options.resolution == 0 {
+        return Err("The resolution must be at least 1".to_string());
+    }
+
+    // By default the equator of the equirectangular map has about as many pixels as the faces
+    // it passes through
+    if options.equirectangular_width == 0 {
+        options.equirectangular_width = options.resolution * 4;
+    }
+    if options.equirectangular_width < 2 {
+        return Err("The equirectangular width must be at least 2".to_string());
+    }
+    Ok(options)
+}
+
+/// Samples the terrain at every (latitude, longitude) pair, spread over all cores.
+fn sample(
+    generator: &planet::Generator,
+    coordinates: Vec<(f64, f64)>,
+    width: usize,
+    height: usize,
+) -> Raster {
+    let chunk_size = (coordinates.len() + num_cpus::get() - 1) / num_cpus::get();
+    let threads: Vec<_> = coordinates
+        .chunks(chunk_size.max(1))
+        .map(|chunk| {
+            let generator = generator.clone();
+            let chunk = chunk.to_vec();
+            thread::spawn(move || generator.surfaces_at(&chunk))
+        })
+        .collect();
+
+    let mut raster = Raster {
+        width,
+        height,
+        heights: Vec::with_capacity(coordinates.len()),
+        normals: Vec::with_capacity(coordinates.len()),
+        colors: Vec::with_capacity(coordinates.len()),
+    };
+    for thread in threads {
+        for sample in thread.join().expect("Sampling thread panicked") {
+            raster.heights.push(sample.height);
+            raster.normals.push(sample.normal);
+            raster.colors.push(sample.material.color);
+        }
+    }
+    raster
+}
+
+/// Writes the height, normal and color images of a raster. Heights are stored as 16-bit
+/// grayscale between `height_min` and `height_max`, normals in world space as 8-bit RGB with
+/// every component mapped from [-1, 1] to [0, 255] and the linear colors of the terrain as 8-bit
+/// sRGB.
+fn write_raster(
+    directory: &Path,
+    name: &str,
+    raster: &Raster,
+    height_min: f64,
+    height_max: f64,
+) -> Result<(), String> {
+    let (width, height) = (raster.width as u32, raster.height as u32);
+    let to_byte = |value: f64| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+
+    // 16-bit PNG samples are big-endian
+    let range = (height_max - height_min).max(std::f64::EPSILON);
+    let heights: Vec<u8> = raster
+        .heights
+        .iter()
+        .flat_map(|height| {
+            let value = ((height - height_min) / range * 65535.0).round() as u16;
+            vec![(value >> 8) as u8, value as u8]
+        })
+        .collect();
+    let normals: Vec<u8> = raster
+        .normals
+        .iter()
+        .flat_map(|normal| {
+            vec![
+                to_byte(normal.x * 0.5 + 0.5),
+                to_byte(normal.y * 0.5 + 0.5),
+                to_byte(normal.z * 0.5 + 0.5),
+            ]
+        })
+        .collect();
+    let colors: Vec<u8> = raster
+        .colors
+        .iter()
+        .flat_map(|color| {
+            vec![
+                to_byte(linear_to_srgb(f64::from(color.x))),
+                to_byte(linear_to_srgb(f64::from(color.y))),
+                to_byte(linear_to_srgb(f64::from(color.z))),
+            ]
+        })
+        .collect();
+
+    let images = [
+        ("height", heights, image::ColorType::Gray(16)),
+        ("normal", normals, image::ColorType::RGB(8)),
+        ("color", colors, image::ColorType::RGB(8)),
+    ];
+    for (kind, data, color_type) in images.iter() {
+        let path = directory.join(format!("{}_{}.png", name, kind));
+        image::save_buffer(&path, data, width, height, *color_type)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Applies the sRGB transfer function to a linear color component
+fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
@@ -0,0 +1,166 @@
+extern crate nalgebra;
+extern crate omniverse;
+
+use nalgebra::Point3;
+use omniverse::planet;
+use std::fs::File;
+use std::io::BufWriter;
+
+const USAGE: &str = "Usage: export_mesh <output.obj|output.ply|output.glb> [--terrain <terrain.yaml>] \
+                     [--lod <level> | --camera <x,y,z>] [--seed <seed>] [--radius <radius>] \
+                     [--precision single|double] [--vertices-per-patch <2^n + 1>]";
+
+/// The file formats a mesh can be exported to
+enum Format {
+    Obj,
+    Ply,
+    Gltf,
+}
+
+/// Settings read from the command line
+struct Options {
+    output: String,
+    format: Format,
+    terrain: String,
+    lod: usize,
+    /// Exports the patches the renderer shows from here instead of a single level
+    camera: Option<Point3<f64>>,
+    seed: u32,
+    radius: f64,
+    precision: planet::Precision,
+    config: planet::RendererConfig,
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let terrain = std::fs::read_to_string(&options.terrain)
+        .map_err(|err| err.to_string())
+        .and_then(|source| planet::Terrain::from_yaml(&source).map_err(|err| err.to_string()));
+    let terrain = match terrain {
+        Ok(terrain) => terrain,
+        Err(err) => {
+            eprintln!("Could not load '{}': {}", options.terrain, err);
+            std::process::exit(1);
+        }
+    };
+
+    let description = planet::Description {
+        radius: options.radius,
+        seed: options.seed,
+        precision: options.precision,
+        sea_level: None,
+    };
+    let generator = planet::Generator::new(description, terrain, options.config);
+    let mesh = match options.camera {
+        Some(camera) => {
+            let geometries: Vec<planet::PatchGeometry> =
+                planet::patches_around(&generator, options.radius, &camera)
+                    .into_iter()
+                    .map(|(_, geometry)| geometry)
+                    .collect();
+            planet::Mesh::from_geometries(&geometries, &options.config)
+        }
+        None => {
+            let locations = planet::patches_at_level(options.lod);
+            planet::Mesh::from_patches(&generator, &options.config, &locations)
+        }
+    };
+
+    let result = File::create(&options.output).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        match options.format {
+            Format::Obj => mesh.write_obj(&mut writer),
+            Format::Ply => mesh.write_ply(&mut writer),
+            Format::Gltf => mesh.write_gltf(&mut writer),
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("Could not write '{}': {}", options.output, err);
+        std::process::exit(1);
+    }
+    eprintln!(
+        "Wrote {} vertices and {} triangles to {}",
+        mesh.positions.len(),
+        mesh.indices.len() / 3,
+        options.output
+    );
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        output: String::new(),
+        format: Format::Obj,
+        terrain: "resources/terrain.yaml".to_string(),
+        lod: 2,
+        camera: None,
+        seed: 0,
+        radius: 400_000.0,
+        precision: planet::Precision::Single,
+        config: planet::RendererConfig::default(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.output = arg;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--terrain" => options.terrain = value.clone(),
+            "--lod" => options.lod = value.parse().map_err(|_| invalid())?,
+            "--camera" => options.camera = Some(parse_point(&value).ok_or_else(invalid)?),
+            "--seed" => options.seed = value.parse().map_err(|_| invalid())?,
+            "--radius" => options.radius = value.parse().map_err(|_| invalid())?,
+            "--vertices-per-patch" => {
+                options.config.vertices_per_patch = value.parse().map_err(|_| invalid())?
+            }
+            "--precision" => options.precision = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    if options.output.is_empty() {
+        return Err("Missing output file".to_string());
+    }
+    let extension = std::path::Path::new(&options.output)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    options.format = match extension.as_ref().map(String::as_str) {
+        Some("obj") => Format::Obj,
+        Some("ply") => Format::Ply,
+        Some("glb") => Format::Gltf,
+        _ => return Err("The output file must end in .obj, .ply or .glb".to_string()),
+    };
+
+    // Every level has four times as many patches as the one before
+    if options.lod > 6 {
+        return Err("The level of detail can be at most 6".to_string());
+    }
+    options.config.validate()?;
+    Ok(options)
+}
+
+/// Parses a point given as three comma separated coordinates.
+fn parse_point(value: &str) -> Option<Point3<f64>> {
+    let coordinates: Vec<f64> = value
+        .split(',')
+        .map(|coordinate| coordinate.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match coordinates[..] {
+        [x, y, z] => Some(Point3::new(x, y, z)),
+        _ => None,
+    }
+}
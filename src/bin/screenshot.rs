@@ -16,6 +16,18 @@ struct Screenshots {
     samples: Option<u8>,
     size: Option<(u32, u32)>,
     screenshots: Vec<ScreenshotInfo>,
+
+    #[serde(default)]
+    renderer: planet::RendererConfig,
+
+    /// The precision the terrain is evaluated in, `single` or `double`
+    #[serde(default)]
+    precision: planet::Precision,
+
+    /// Height of the ocean surface relative to the radius, the planet has no ocean if it is left
+    /// out
+    #[serde(default)]
+    sea_level: Option<f64>,
 }
 
 #[derive(Deserialize)]
@@ -74,18 +86,35 @@ fn main() {
 
 
     let terrain_str = std::fs::read_to_string("resources/terrain.yaml").expect("Missing resource file: resources/terrain.yaml");
-    let terrain_desc = serde_yaml::from_str(&terrain_str).expect("Corrupt JSON in file: resources/terrain.yaml");
+    let terrain_desc = match planet::Terrain::from_yaml(&terrain_str) {
+        Ok(terrain_desc) => terrain_desc,
+        Err(err) => {
+            error!("Invalid terrain in file resources/terrain.yaml: {}", err);
+            std::process::exit(1);
+        }
+    };
 
     let events_loop = glutin::EventsLoop::new();
     let display = create(&events_loop);
 
     // Initialize a planet
-    let planet_desc = planet::Description { radius: 400000.0 };
+    let planet_desc = planet::Description {
+        radius: 400000.0,
+        seed: 0,
+        precision: screenshot_infos.precision,
+        sea_level: screenshot_infos.sea_level,
+    };
     let planet_transform = Transform::identity();
-    let geometry_provider = planet::Generator::new(planet_desc.clone(), terrain_desc);
+    let geometry_provider =
+        planet::Generator::new(planet_desc.clone(), terrain_desc, screenshot_infos.renderer);
     let async_geometry_provider = planet::SyncGeometryProvider::new(geometry_provider);
     let mut planet_renderer =
-        planet::Renderer::new(&display, planet_desc.clone(), async_geometry_provider)
+        planet::Renderer::new(
+            &display,
+            planet_desc.clone(),
+            screenshot_infos.renderer,
+            async_geometry_provider,
+        )
             .expect("Could not instantiate renderer");
 
     for screenshot in screenshot_infos.screenshots.iter() {
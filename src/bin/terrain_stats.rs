@@ -0,0 +1,301 @@
+extern crate nalgebra;
+extern crate omniverse;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+
+use nalgebra::{Point2, Point3};
+use omniverse::planet::{self, GeometryProvider};
+use std::thread;
+
+const USAGE: &str = "Usage: terrain_stats [terrain.yaml] [--seed <seed>] [--radius <radius>] \
+                     [--precision single|double] [--sea-level <height>] \
+                     [--resolution <samples per face edge>] [--bins <histogram bins>]";
+
+/// Settings read from the command line
+struct Options {
+    terrain: String,
+    seed: u32,
+    radius: f64,
+    precision: planet::Precision,
+    sea_level: f64,
+    resolution: usize,
+    bins: usize,
+}
+
+/// The terrain at a single sample point
+struct Sample {
+    /// Height above the radius of the planet
+    height: f64,
+
+    /// Angle between the surface normal and the up vector in degrees
+    slope: f64,
+
+    /// The area of the unit sphere covered by this sample
+    area: f64,
+}
+
+/// Statistics of the entire surface of a planet. All averages and ratios are weighted by surface
+/// area.
+#[derive(Serialize)]
+struct Statistics {
+    samples: usize,
+    height: HeightStatistics,
+    sea_level: f64,
+
+    /// Fraction of the surface above `sea_level`
+    land_ratio: f64,
+
+    /// Fraction of the surface at or below `sea_level`
+    ocean_ratio: f64,
+
+    slope: SlopeStatistics,
+}
+
+#[derive(Serialize)]
+struct HeightStatistics {
+    min: f64,
+    max: f64,
+    mean: f64,
+    histogram: Histogram,
+}
+
+/// Slopes in degrees
+#[derive(Serialize)]
+struct SlopeStatistics {
+    min: f64,
+    max: f64,
+    mean: f64,
+    histogram: Histogram,
+}
+
+/// The fraction of the surface that falls into each of a number of evenly sized bins between
+/// `min` and `max`.
+#[derive(Serialize)]
+struct Histogram {
+    min: f64,
+    max: f64,
+    bins: Vec<f64>,
+}
+
+impl Histogram {
+    fn new(
+        min: f64,
+        max: f64,
+        count: usize,
+        values: impl Iterator<Item = (f64, f64)>,
+    ) -> Histogram {
+        let mut bins = vec![0.0; count];
+        let mut total = 0.0;
+        let range = max - min;
+        for (value, area) in values {
+            let bin = if range > 0.0 {
+                (((value - min) / range * count as f64) as usize).min(count - 1)
+            } else {
+                0
+            };
+            bins[bin] += area;
+            total += area;
+        }
+        if total > 0.0 {
+            for bin in bins.iter_mut() {
+                *bin /= total;
+            }
+        }
+        Histogram { min, max, bins }
+    }
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let terrain = std::fs::read_to_string(&options.terrain)
+        .map_err(|err| err.to_string())
+        .and_then(|source| planet::Terrain::from_yaml(&source).map_err(|err| err.to_string()));
+    let terrain = match terrain {
+        Ok(terrain) => terrain,
+        Err(err) => {
+            eprintln!("Could not load '{}': {}", options.terrain, err);
+            std::process::exit(1);
+        }
+    };
+
+    let description = planet::Description {
+        radius: options.radius,
+        seed: options.seed,
+        precision: options.precision,
+        sea_level: Some(options.sea_level),
+    };
+    let generator = planet::Generator::new(description, terrain, planet::RendererConfig::default());
+
+    // Every face is sampled on its own thread
+    let resolution = options.resolution;
+    let radius = options.radius;
+    let threads: Vec<_> = planet::Face::values()
+        .map(|&face| {
+            let generator = generator.clone();
+            thread::spawn(move || sample_face(&generator, face, resolution, radius))
+        })
+        .collect();
+    let samples: Vec<Sample> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().expect("Sampling thread panicked"))
+        .collect();
+
+    let statistics = compute_statistics(&samples, &options);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&statistics).expect("Could not serialize statistics")
+    );
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        terrain: "resources/terrain.yaml".to_string(),
+        seed: 0,
+        radius: 400_000.0,
+        precision: planet::Precision::Single,
+        sea_level: 0.0,
+        resolution: 256,
+        bins: 32,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.terrain = arg;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--seed" => options.seed = value.parse().map_err(|_| invalid())?,
+            "--radius" => options.radius = value.parse().map_err(|_| invalid())?,
+            "--sea-level" => options.sea_level = value.parse().map_err(|_| invalid())?,
+            "--resolution" => options.resolution = value.parse().map_err(|_| invalid())?,
+            "--bins" => options.bins = value.parse().map_err(|_| invalid())?,
+            "--precision" => options.precision = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    if options.resolution < 2 {
+        return Err("The resolution must be at least 2".to_string());
+    }
+    if options.bins == 0 {
+        return Err("The histogram needs at least one bin".to_string());
+    }
+    Ok(options)
+}
+
+/// Samples a face of the planet on a regular grid of `resolution` by `resolution` points.
+fn sample_face(
+    generator: &planet::Generator,
+    face: planet::Face,
+    resolution: usize,
+    radius: f64,
+) -> Vec<Sample> {
+    let positions: Vec<Point3<f64>> = (0..resolution * resolution)
+        .map(|i| {
+            let x = (i % resolution) as f64 + 0.5;
+            let y = (i / resolution) as f64 + 0.5;
+            let offset = Point2::new(x / resolution as f64, y / resolution as f64);
+            generator.position_at(face, offset)
+        })
+        .collect();
+    let position = |x: usize, y: usize| positions[y * resolution + x].coords;
+
+    let mut samples = Vec::with_capacity(resolution * resolution);
+    for y in 0..resolution {
+        for x in 0..resolution {
+            // Tangents are estimated from the neighbouring samples, one-sided at the edges
+            let (x0, x1) = (x.saturating_sub(1), (x + 1).min(resolution - 1));
+            let (y0, y1) = (y.saturating_sub(1), (y + 1).min(resolution - 1));
+            let center = position(x, y);
+            let dir = center.normalize();
+
+            // The samples are not evenly spread over the sphere, so every sample is weighted by
+            // the area of the unit sphere it covers
+            let dir_u = position(x1, y).normalize() - position(x0, y).normalize();
+            let dir_v = position(x, y1).normalize() - position(x, y0).normalize();
+            let area = dir_u.cross(&dir_v).norm() / ((x1 - x0) * (y1 - y0)) as f64;
+
+            let tangent_u = position(x1, y) - position(x0, y);
+            let tangent_v = position(x, y1) - position(x, y0);
+            let normal = tangent_u.cross(&tangent_v).normalize();
+            let slope = normal.dot(&dir).abs().min(1.0).acos().to_degrees();
+
+            samples.push(Sample {
+                height: center.norm() - radius,
+                slope,
+                area,
+            });
+        }
+    }
+    samples
+}
+
+fn compute_statistics(samples: &[Sample], options: &Options) -> Statistics {
+    let total_area: f64 = samples.iter().map(|sample| sample.area).sum();
+    let mean = |value: &Fn(&Sample) -> f64| {
+        samples
+            .iter()
+            .map(|sample| value(sample) * sample.area)
+            .sum::<f64>()
+            / total_area
+    };
+    let range = |value: &Fn(&Sample) -> f64| {
+        samples
+            .iter()
+            .fold((std::f64::MAX, std::f64::MIN), |(min, max), sample| {
+                (min.min(value(sample)), max.max(value(sample)))
+            })
+    };
+
+    let (min_height, max_height) = range(&|sample| sample.height);
+    let (min_slope, max_slope) = range(&|sample| sample.slope);
+    let land_area: f64 = samples
+        .iter()
+        .filter(|sample| sample.height > options.sea_level)
+        .map(|sample| sample.area)
+        .sum();
+
+    Statistics {
+        samples: samples.len(),
+        height: HeightStatistics {
+            min: min_height,
+            max: max_height,
+            mean: mean(&|sample| sample.height),
+            histogram: Histogram::new(
+                min_height,
+                max_height,
+                options.bins,
+                samples.iter().map(|sample| (sample.height, sample.area)),
+            ),
+        },
+        sea_level: options.sea_level,
+        land_ratio: land_area / total_area,
+        ocean_ratio: 1.0 - land_area / total_area,
+        slope: SlopeStatistics {
+            min: min_slope,
+            max: max_slope,
+            mean: mean(&|sample| sample.slope),
+            histogram: Histogram::new(
+                0.0,
+                90.0,
+                options.bins,
+                samples.iter().map(|sample| (sample.slope, sample.area)),
+            ),
+        },
+    }
+}
@@ -23,14 +23,83 @@ use std::time::Duration;
 use std::path::PathBuf;
 use std::env;
 
-fn create_generator(planet_desc: planet::Description) -> Result<planet::ThreadpoolGeometryProvider<planet::Generator>, Box<std::error::Error>> {
+/// The generator of the planet, behind a disk cache unless the cache could not be created
+#[derive(Clone)]
+enum Provider {
+    Cached(planet::PatchCache),
+    Uncached(planet::Generator),
+}
+
+impl planet::GeometryProvider for Provider {
+    fn compute_geometry(&self, patch: planet::PatchLocation) -> planet::PatchGeometry {
+        match self {
+            Provider::Cached(cache) => planet::GeometryProvider::compute_geometry(cache, patch),
+            Provider::Uncached(generator) => planet::GeometryProvider::compute_geometry(generator, patch),
+        }
+    }
+
+    fn position_at(&self, face: planet::Face, offset: nalgebra::Point2<f64>) -> nalgebra::Point3<f64> {
+        match self {
+            Provider::Cached(cache) => planet::GeometryProvider::position_at(cache, face, offset),
+            Provider::Uncached(generator) => planet::GeometryProvider::position_at(generator, face, offset),
+        }
+    }
+}
+
+type GeometryProvider = planet::ThreadpoolGeometryProvider<Provider>;
+
+fn create_generator(planet_desc: planet::Description, renderer_config: planet::RendererConfig) -> Result<GeometryProvider, Box<std::error::Error>> {
     let terrain_str = fs::read_to_string("resources/terrain.yaml")?;
-    let terrain_desc = serde_yaml::from_str(&terrain_str)?;
+    let terrain_desc = planet::Terrain::from_yaml(&terrain_str)?;
 
-    let geometry_provider = planet::Generator::new(planet_desc, terrain_desc );
+    // Generated patches are kept on disk so they don't have to be generated again after a restart,
+    // without a usable cache directory they are generated every time
+    let generator = planet::Generator::new(planet_desc, terrain_desc, renderer_config);
+    let geometry_provider = match planet::PatchCache::new(generator.clone(), "cache/patches") {
+        Ok(cache) => Provider::Cached(cache),
+        Err(err) => {
+            warn!("Could not create the patch cache, patches are not cached: {}", err);
+            Provider::Uncached(generator)
+        }
+    };
     Ok(planet::ThreadpoolGeometryProvider::new(geometry_provider))
 }
 
+/// Reads the renderer configuration from resources/renderer.yaml, the defaults are used if the file
+/// does not exist.
+fn load_renderer_config() -> Result<planet::RendererConfig, Box<std::error::Error>> {
+    match fs::read_to_string("resources/renderer.yaml") {
+        Ok(config_str) => Ok(serde_yaml::from_str(&config_str)?),
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(planet::RendererConfig::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads the planet seed, the first argument that is not an option, the precision and the sea
+/// level from the command line. Without a sea level the ocean surface is at the radius of the
+/// planet, `none` leaves out the ocean.
+fn parse_arguments() -> Result<(u32, planet::Precision, Option<f64>), String> {
+    let mut seed = 0;
+    let mut precision = planet::Precision::Single;
+    let mut sea_level = Some(0.0);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--precision" {
+            let value = args.next().ok_or("Missing value for --precision")?;
+            precision = value.parse()?;
+        } else if arg == "--sea-level" {
+            let value = args.next().ok_or("Missing value for --sea-level")?;
+            sea_level = match value.as_str() {
+                "none" => None,
+                _ => Some(value.parse().map_err(|_| format!("Invalid sea level: {}", value))?),
+            };
+        } else {
+            seed = arg.parse().map_err(|_| format!("Invalid seed: {}", arg))?;
+        }
+    }
+    Ok((seed, precision, sea_level))
+}
+
 fn main() {
     use glium::glutin;
     use glium::Surface;
@@ -39,7 +108,9 @@ fn main() {
 
     let mut events_loop = glutin::EventsLoop::new();
     let window_builder = glutin::WindowBuilder::new().with_title("Omniverse");
-    let context = glutin::ContextBuilder::new().with_vsync(true);
+    // The shaders output linear colors which the framebuffer converts to sRGB, as in the
+    // screenshots and the color maps of bake_maps
+    let context = glutin::ContextBuilder::new().with_vsync(true).with_srgb(true);
     let display = glium::Display::new(window_builder, context, &events_loop).unwrap();
     let window = display.gl_window();
 //    let hidpi_factor = display.gl_window().get_hidpi_factor();
@@ -57,11 +128,36 @@ fn main() {
     camera.set_far(200_00000.0);
     camera.pitch(std::f64::consts::PI*0.5);
 
-    let planet_desc = planet::Description { radius: 400_000.0 };
+    let (seed, precision, sea_level) = match parse_arguments() {
+        Ok(arguments) => arguments,
+        Err(err) => {
+            error!("{}\nUsage: omniverse [seed] [--precision single|double] [--sea-level <height>|none]", err);
+            return;
+        }
+    };
+    let planet_desc = planet::Description {
+        radius: 400_000.0,
+        seed,
+        precision,
+        sea_level,
+    };
     let planet_transform = Transform::identity();
-    let generator = create_generator(planet_desc.clone()).unwrap();
+    let renderer_config = match load_renderer_config() {
+        Ok(renderer_config) => renderer_config,
+        Err(err) => {
+            error!("Error loading renderer configuration: {}", err);
+            return;
+        }
+    };
+    let generator = match create_generator(planet_desc.clone(), renderer_config) {
+        Ok(generator) => generator,
+        Err(err) => {
+            error!("Error loading planet description: {}", err);
+            return;
+        }
+    };
     let mut planet_renderer =
-        planet::Renderer::new(&display, planet_desc.clone(), generator)
+        planet::Renderer::new(&display, planet_desc.clone(), renderer_config, generator)
             .expect("Could not instantiate renderer");
 
     // Create a channel to receive file modification events
@@ -109,17 +205,19 @@ fn main() {
         while let Ok(event) = rx.try_recv() {
             let mut file_modified = |path:PathBuf| {
                 if let Some(diff) = pathdiff::diff_paths(path.as_path(), resources_directory.as_path()) {
-                    match diff.as_path().to_str().unwrap() {
-                        "terrain.yaml" => {
-                            match create_generator(planet_desc.clone()) {
-                                Ok(generator) => {
-                                    planet_renderer.set_generator(generator);
-                                    info!("Reloaded planet description from file")
-                                },
-                                Err(err) =>error!("Error reloading planet description: {}", err),
-                            };
-                        }
-                        _ => {}
+                    // Heightmap images are referenced from the terrain file so they trigger a
+                    // reload as well
+                    let is_terrain = diff.as_path().to_str() == Some("terrain.yaml");
+                    let is_heightmap = diff.extension().map_or(false, |ext| ext == "png");
+                    if is_terrain || is_heightmap {
+                        match create_generator(planet_desc.clone(), renderer_config) {
+                            Ok(generator) => {
+                                planet_renderer.set_generator(generator);
+                                info!("Reloaded planet description from file")
+                            },
+                            // The previous generator stays active until the file is fixed
+                            Err(err) => error!("Error reloading planet description: {}", err),
+                        };
                     }
                 }
             };
@@ -0,0 +1,163 @@
+use crate::planet::color_ramp::{ColorRamp, Material};
+use crate::planet::terrain::{TerrainContext, TerrainLayer};
+use nalgebra::{Vector3, Vector4};
+
+/// Fields that describe the climate of a planet. Both fields are expected to lie between 0 and 1.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Climate {
+    /// Moisture of the terrain, 0 is dry and 1 is wet. Defaults to 0.5 everywhere.
+    #[serde(default)]
+    pub moisture: Option<TerrainLayer>,
+
+    /// Temperature of the terrain, 0 is cold and 1 is hot. Defaults to the cosine of the latitude.
+    #[serde(default)]
+    pub temperature: Option<TerrainLayer>,
+}
+
+/// The properties of a single point on the surface that are used to select a biome.
+#[derive(Copy, Clone, Debug)]
+pub struct BiomeSample {
+    /// Absolute latitude in degrees, 0 at the equator and 90 at either pole
+    pub latitude: f32,
+
+    /// Height of the terrain relative to the radius of the planet
+    pub height: f32,
+
+    /// Angle between the surface normal and the up vector in degrees
+    pub slope: f32,
+
+    pub moisture: f32,
+    pub temperature: f32,
+}
+
+impl Climate {
+    /// Computes the biome sample of the point in direction `dir` with the specified height and
+    /// surface normal.
+    pub fn sample(
+        &self,
+        dir: &Vector3<f32>,
+        height: f32,
+        normal: &Vector3<f32>,
+        context: &mut TerrainContext,
+    ) -> BiomeSample {
+        let latitude = dir.y.max(-1.0).min(1.0).asin();
+        let moisture = match &self.moisture {
+            Some(layer) => layer.compute_height(dir, context),
+            None => 0.5,
+        };
+        let temperature = match &self.temperature {
+            Some(layer) => layer.compute_height(dir, context),
+            None => latitude.cos(),
+        };
+
+        BiomeSample {
+            latitude: latitude.abs().to_degrees(),
+            height,
+            slope: normal.dot(dir).max(-1.0).min(1.0).acos().to_degrees(),
+            moisture,
+            temperature,
+        }
+    }
+}
+
+/// A range of values a biome is present in. Outside of the range the biome fades out over
+/// `blend` units.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Condition {
+    #[serde(default)]
+    pub min: Option<f32>,
+
+    #[serde(default)]
+    pub max: Option<f32>,
+
+    #[serde(default)]
+    pub blend: f32,
+}
+
+impl Condition {
+    /// Returns how much the value satisfies this condition, 1 is completely and 0 is not at all.
+    fn weight(&self, value: f32) -> f32 {
+        let edge = |distance: f32| {
+            if self.blend > 0.0 {
+                (distance / self.blend + 1.0).max(0.0).min(1.0)
+            } else if distance >= 0.0 {
+                1.0
+            } else {
+                0.0
+            }
+        };
+
+        self.min.map_or(1.0, |min| edge(value - min)) * self.max.map_or(1.0, |max| edge(max - value))
+    }
+}
+
+/// A region of the planet with its own colors. A biome without conditions covers the entire
+/// planet.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Biome {
+    #[serde(default)]
+    pub name: String,
+
+    #[serde(default)]
+    pub latitude: Option<Condition>,
+
+    #[serde(default)]
+    pub height: Option<Condition>,
+
+    #[serde(default)]
+    pub slope: Option<Condition>,
+
+    #[serde(default)]
+    pub moisture: Option<Condition>,
+
+    #[serde(default)]
+    pub temperature: Option<Condition>,
+
+    pub colors: ColorRamp,
+}
+
+impl Biome {
+    /// Returns how much the sample belongs to this biome, 1 is completely and 0 is not at all.
+    pub fn weight(&self, sample: &BiomeSample) -> f32 {
+        let weight = |condition: &Option<Condition>, value: f32| {
+            condition.as_ref().map_or(1.0, |condition| condition.weight(value))
+        };
+
+        weight(&self.latitude, sample.latitude)
+            * weight(&self.height, sample.height)
+            * weight(&self.slope, sample.slope)
+            * weight(&self.moisture, sample.moisture)
+            * weight(&self.temperature, sample.temperature)
+    }
+}
+
+/// Computes the material of a sample by layering the biomes on top of each other. Biomes earlier
+/// in the list take precedence over later ones, whatever is not covered by any biome uses the
+/// `fallback` colors.
+pub fn material_at(biomes: &[Biome], fallback: &ColorRamp, sample: &BiomeSample) -> Material {
+    let mut color = Vector4::zeros();
+    let mut roughness = 0.0;
+    let mut remaining = 1.0;
+
+    for biome in biomes {
+        let weight = biome.weight(sample) * remaining;
+        if weight > 0.0 {
+            let material = biome.colors.material_at(sample.height);
+            color += material.color * weight;
+            roughness += material.roughness * weight;
+            remaining -= weight;
+        }
+
+        if remaining <= 0.0 {
+            break;
+        }
+    }
+
+    if remaining > 0.0 {
+        let material = fallback.material_at(sample.height);
+        color += material.color * remaining;
+        roughness += material.roughness * remaining;
+    }
+
+    Material { color, roughness }
+}
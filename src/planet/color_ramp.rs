@@ -0,0 +1,134 @@
+use nalgebra::Vector4;
+
+/// Determines how colors are blended between two neighbouring stops of a `ColorRamp`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Interpolation {
+    /// Use the color of the lower stop until the next stop is reached
+    Step,
+
+    /// Linearly blend between two stops
+    Linear,
+
+    /// Blend between two stops using a smoothstep curve
+    Smooth,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation::Linear
+    }
+}
+
+/// A single entry in a `ColorRamp`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ColorStop {
+    /// The terrain height at which this stop is placed
+    pub height: f32,
+
+    /// The RGB color of the stop
+    pub color: [f32; 3],
+
+    #[serde(default = "default_alpha")]
+    pub alpha: f32,
+
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+}
+
+fn default_alpha() -> f32 {
+    1.0
+}
+
+fn default_roughness() -> f32 {
+    1.0
+}
+
+/// Surface properties at a single point of the terrain.
+#[derive(Copy, Clone, Debug)]
+pub struct Material {
+    pub color: Vector4<f32>,
+    pub roughness: f32,
+}
+
+/// Maps a terrain height to a color. The stops must be sorted by height.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ColorRamp {
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    pub stops: Vec<ColorStop>,
+}
+
+impl Default for ColorRamp {
+    fn default() -> Self {
+        let stop = |height, color| ColorStop {
+            height,
+            color,
+            alpha: default_alpha(),
+            roughness: default_roughness(),
+        };
+
+        ColorRamp {
+            interpolation: Interpolation::Linear,
+            stops: vec![
+                stop(0.0, [0.0, 0.0, 0.6]),
+                stop(50.0, [0.0, 0.5, 0.0]),
+                stop(700.0, [0.0, 0.5, 0.0]),
+                stop(1000.0, [0.5, 0.5, 0.5]),
+            ],
+        }
+    }
+}
+
+impl ColorRamp {
+    /// Returns the material at the specified height
+    pub fn material_at(&self, height: f32) -> Material {
+        let upper = match self.stops.iter().position(|stop| height < stop.height) {
+            Some(index) => index,
+            None => {
+                return self
+                    .stops
+                    .last()
+                    .map(ColorStop::material)
+                    .unwrap_or_else(fallback_material)
+            }
+        };
+        if upper == 0 {
+            return self.stops[0].material();
+        }
+
+        let low = &self.stops[upper - 1];
+        let hi = &self.stops[upper];
+
+        let t = (height - low.height) / (hi.height - low.height);
+        let a = match self.interpolation {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => t,
+            Interpolation::Smooth => t * t * (3.0 - 2.0 * t),
+        };
+
+        let low = low.material();
+        let hi = hi.material();
+        Material {
+            color: low.color * (1.0 - a) + hi.color * a,
+            roughness: low.roughness * (1.0 - a) + hi.roughness * a,
+        }
+    }
+}
+
+impl ColorStop {
+    fn material(&self) -> Material {
+        Material {
+            color: Vector4::new(self.color[0], self.color[1], self.color[2], self.alpha),
+            roughness: self.roughness,
+        }
+    }
+}
+
+/// The material used when a ramp has no stops at all
+fn fallback_material() -> Material {
+    Material {
+        color: Vector4::new(1.0, 0.0, 1.0, 1.0),
+        roughness: 1.0,
+    }
+}
@@ -0,0 +1,235 @@
+use crate::planet::Face;
+use nalgebra::{Point2, Point3, Vector3};
+
+/// A position relative to a planet in latitude, longitude and altitude. Latitude and longitude are
+/// in radians: latitude is 0 at the equator and positive towards the +Y pole, longitude is 0
+/// towards +Z and positive towards +X.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Geodetic {
+    pub latitude: f64,
+    pub longitude: f64,
+
+    /// Distance above the radius of the planet, not above the terrain
+    pub altitude: f64,
+}
+
+impl Geodetic {
+    /// Converts a position relative to the center of a planet with the given radius.
+    pub fn from_position(position: &Point3<f64>, radius: f64) -> Geodetic {
+        let distance = position.coords.norm();
+        let (latitude, longitude) = lat_lon(&(position.coords / distance));
+        Geodetic {
+            latitude,
+            longitude,
+            altitude: distance - radius,
+        }
+    }
+
+    /// Returns the position relative to the center of a planet with the given radius.
+    pub fn to_position(&self, radius: f64) -> Point3<f64> {
+        Point3::from_coordinates(
+            direction(self.latitude, self.longitude) * (radius + self.altitude),
+        )
+    }
+}
+
+/// Returns the direction on the unit sphere of a latitude and longitude in radians.
+pub fn direction(latitude: f64, longitude: f64) -> Vector3<f64> {
+    Vector3::new(
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+        latitude.cos() * longitude.cos(),
+    )
+}
+
+/// Returns the latitude and longitude in radians of a direction on the unit sphere.
+pub fn lat_lon(dir: &Vector3<f64>) -> (f64, f64) {
+    (dir.y.max(-1.0).min(1.0).asin(), dir.x.atan2(dir.z))
+}
+
+/// Maps a position on the surface of the cube with corners at -1 and 1 to the unit sphere. The
+/// mapping spreads the vertices of a patch more evenly over the sphere than normalizing.
+pub fn cube_to_sphere(pos: Vector3<f64>) -> Vector3<f64> {
+    let pos_squared = Vector3::new(pos.x * pos.x, pos.y * pos.y, pos.z * pos.z);
+    let a = Vector3::new(pos_squared.y, pos_squared.z, pos_squared.x) * 0.5;
+    let b = Vector3::new(pos_squared.z, pos_squared.x, pos_squared.y) * 0.5;
+    Vector3::new(
+        pos.x * f64::sqrt(1.0 - a.x - b.x + pos_squared.y * pos_squared.z / 3.0),
+        pos.y * f64::sqrt(1.0 - a.y - b.y + pos_squared.z * pos_squared.x / 3.0),
+        pos.z * f64::sqrt(1.0 - a.z - b.z + pos_squared.x * pos_squared.y / 3.0),
+    )
+}
+
+/// The inverse of `cube_to_sphere`: maps a direction on the unit sphere to the surface of the
+/// cube with corners at -1 and 1.
+pub fn sphere_to_cube(dir: &Vector3<f64>) -> Vector3<f64> {
+    let (x, y, z) = (dir.x.abs(), dir.y.abs(), dir.z.abs());
+    if x >= y && x >= z {
+        let (y, z) = invert_face(dir.y, dir.z);
+        Vector3::new(dir.x.signum(), y, z)
+    } else if y >= z {
+        let (z, x) = invert_face(dir.z, dir.x);
+        Vector3::new(x, dir.y.signum(), z)
+    } else {
+        let (x, y) = invert_face(dir.x, dir.y);
+        Vector3::new(x, y, dir.z.signum())
+    }
+}
+
+/// Solves the mapping of a single cube face for the two coordinates along the face. With the
+/// third coordinate at 1 the mapping is `s = a * sqrt(1/2 - b²/6)` and `t = b * sqrt(1/2 - a²/6)`.
+/// Substituting `p = a²b²/3` turns this into a quadratic equation in `p`.
+fn invert_face(s: f64, t: f64) -> (f64, f64) {
+    let u = 2.0 * s * s;
+    let v = 2.0 * t * t;
+    let c = 3.0 - u - v;
+
+    // The smaller root is the one that keeps both coordinates within the face
+    let p = 0.5 * (c - (c * c - 4.0 * u * v).max(0.0).sqrt());
+    let a = (u + p).max(0.0).min(1.0).sqrt();
+    let b = (v + p).max(0.0).min(1.0).sqrt();
+    (a * s.signum(), b * t.signum())
+}
+
+/// Returns the face that contains the direction `dir` and the offset from the top-left corner of
+/// that face between 0 and 1, as used by `PatchLocation`.
+pub fn face_offset(dir: &Vector3<f64>) -> (Face, Point2<f64>) {
+    let cube = sphere_to_cube(&dir.normalize());
+
+    let mut result = (Face::Front, Point2::new(0.5, 0.5));
+    let mut max_depth = std::f64::MIN;
+    for face in Face::values() {
+        let local = face.orientation().inverse() * cube;
+        if local.z > max_depth {
+            max_depth = local.z;
+            result = (
+                *face,
+                Point2::new(
+                    ((local.x + 1.0) * 0.5).max(0.0).min(1.0),
+                    ((local.y + 1.0) * 0.5).max(0.0).min(1.0),
+                ),
+            );
+        }
+    }
+    result
+}
+
+/// Returns the direction on the unit sphere at `offset` from the top-left corner of `face`. This
+/// is the inverse of `face_offset`.
+pub fn face_direction(face: Face, offset: &Point2<f64>) -> Vector3<f64> {
+    cube_to_sphere(face.orientation() * Vector3::new(offset.x - 0.5, offset.y - 0.5, 0.5) * 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planet::quad_tree::Child;
+    use crate::planet::PatchLocation;
+    use std::f64::consts::PI;
+
+    /// Offsets along a face edge, including both edges and a point just inside them
+    const OFFSETS: [f64; 7] = [0.0, 1.0e-6, 0.1, 0.5, 0.77, 1.0 - 1.0e-6, 1.0];
+
+    fn assert_close(a: &Vector3<f64>, b: &Vector3<f64>, tolerance: f64) {
+        assert!((a - b).norm() < tolerance, "{:?} != {:?}", a, b);
+    }
+
+    /// Returns true if two angles are the same modulo a full turn
+    fn same_angle(a: f64, b: f64) -> bool {
+        let difference = (a - b).rem_euclid(2.0 * PI);
+        difference < 1.0e-9 || 2.0 * PI - difference < 1.0e-9
+    }
+
+    #[test]
+    fn sphere_to_cube_inverts_cube_to_sphere() {
+        for face in Face::values() {
+            for &x in OFFSETS.iter() {
+                for &y in OFFSETS.iter() {
+                    let cube = face.orientation() * Vector3::new(x * 2.0 - 1.0, y * 2.0 - 1.0, 1.0);
+                    let dir = cube_to_sphere(cube);
+                    assert!((dir.norm() - 1.0).abs() < 1.0e-12);
+                    assert_close(&sphere_to_cube(&dir), &cube, 1.0e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn face_offset_inverts_face_direction() {
+        for &face in Face::values() {
+            for &x in OFFSETS[1..OFFSETS.len() - 1].iter() {
+                for &y in OFFSETS[1..OFFSETS.len() - 1].iter() {
+                    let (result_face, offset) =
+                        face_offset(&face_direction(face, &Point2::new(x, y)));
+                    assert_eq!(result_face, face);
+                    assert!((offset.x - x).abs() < 1.0e-9 && (offset.y - y).abs() < 1.0e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn geodetic_round_trip() {
+        let radius = 400_000.0;
+        let latitudes = [-0.5 * PI, -1.0, 0.0, 0.3, 0.5 * PI];
+        let longitudes = [-PI, -2.0, 0.0, 1.5, PI];
+        for &latitude in latitudes.iter() {
+            for &longitude in longitudes.iter() {
+                for &altitude in [-1000.0, 0.0, 25_000.0].iter() {
+                    let geodetic = Geodetic {
+                        latitude,
+                        longitude,
+                        altitude,
+                    };
+                    let result = Geodetic::from_position(&geodetic.to_position(radius), radius);
+                    assert!((result.latitude - latitude).abs() < 1.0e-6);
+                    assert!((result.altitude - altitude).abs() < 1.0e-6);
+
+                    // The longitude is undefined at the poles
+                    if latitude.abs() < 0.5 * PI {
+                        assert!(
+                            same_angle(result.longitude, longitude),
+                            "{} != {}",
+                            result.longitude,
+                            longitude
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lat_lon_inverts_direction() {
+        let dir = direction(0.4, -2.5);
+        let (latitude, longitude) = lat_lon(&dir);
+        assert!((latitude - 0.4).abs() < 1.0e-12);
+        assert!((longitude + 2.5).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn patch_boundaries() {
+        let root: PatchLocation = Face::Front.into();
+        let top_left = root.top_left();
+        let top_right = root.top_right();
+
+        // A point on the boundary between two patches is in both and belongs to the right one
+        let boundary = Point2::new(0.5, 0.25);
+        assert!(top_left.contains(Face::Front, &boundary));
+        assert!(top_right.contains(Face::Front, &boundary));
+        assert!(!top_left.contains(Face::Back, &boundary));
+        assert!(!root.bottom_left().contains(Face::Front, &boundary));
+        assert_eq!(root.child_containing(&boundary), Child::TopRight);
+
+        let center = top_left.center();
+        assert_eq!(top_left.child_containing(&center), Child::BottomRight);
+        assert_eq!(
+            root.child_containing(&Point2::new(0.0, 0.0)),
+            Child::TopLeft
+        );
+        assert_eq!(
+            root.child_containing(&Point2::new(1.0, 1.0)),
+            Child::BottomRight
+        );
+    }
+}
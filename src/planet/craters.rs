@@ -0,0 +1,286 @@
+use crate::planet::terrain::{combine_seeds, hash};
+use nalgebra::Vector3;
+use std::f32::consts::PI;
+
+/// The maximum number of size classes a crater field is divided in
+pub const MAX_LEVELS: usize = 16;
+
+/// The largest supported exponent of the size distribution. Cells shrink faster than craters for
+/// steeper distributions, so the number of cells visited per sample grows without bound.
+pub const MAX_EXPONENT: f32 = 2.0;
+
+/// The maximum number of craters a single cell of the crater grid can contain. Cells of dense
+/// crater fields are made smaller to stay within this limit.
+const MAX_CRATERS_PER_CELL: u32 = 8;
+
+/// A field of impact craters scattered over the sphere.
+///
+/// Craters are divided in size classes (levels) where every level contains craters half the
+/// radius of the previous level. The number of craters per level follows a power law: the number
+/// of craters with a radius larger than `r` is proportional to `r^-exponent`. Each level is
+/// scattered over a 3D grid of cells, every cell contains a deterministic random set of craters.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Craters {
+    #[serde(default)]
+    pub seed: u32,
+
+    /// Radius of the smallest craters relative to the radius of the planet. Must be larger than
+    /// `max_radius / 2^16`, a field has at most 16 size classes.
+    pub min_radius: f32,
+
+    /// Radius of the largest craters relative to the radius of the planet
+    pub max_radius: f32,
+
+    /// Exponent of the power law of the crater size distribution, at most 2
+    #[serde(default = "default_exponent")]
+    pub exponent: f32,
+
+    /// The number of craters of the largest size class on the entire planet
+    pub density: f32,
+
+    /// Depth of the largest craters, the depth of smaller craters scales with their radius
+    pub depth: f32,
+
+    /// Height of the rim relative to the depth of the crater
+    #[serde(default = "default_rim_height")]
+    pub rim_height: f32,
+
+    /// Height of the central peak relative to the depth of the crater
+    #[serde(default)]
+    pub peak_height: f32,
+
+    /// Distance over which the rim falls off outside of the crater, relative to its radius
+    #[serde(default = "default_ejecta")]
+    pub ejecta: f32,
+
+    /// Mixed into the seed to tell apart crater fields at different places in a terrain, see
+    /// `Terrain::from_yaml`
+    #[serde(skip)]
+    pub salt: u32,
+}
+
+fn default_exponent() -> f32 {
+    2.0
+}
+
+fn default_rim_height() -> f32 {
+    0.2
+}
+
+fn default_ejecta() -> f32 {
+    1.0
+}
+
+/// Parameters of a single size class of craters
+struct Level {
+    index: u32,
+    seed: u32,
+    cell_size: f32,
+
+    /// The radius of the largest craters in this level
+    radius: f32,
+
+    /// The expected number of craters in a single cell
+    craters_per_cell: f32,
+
+    /// The number of neighboring cells in every direction that may contain craters influencing a
+    /// point within a cell
+    reach: i32,
+}
+
+impl Craters {
+    /// Computes the sum of the height of all craters that influence the direction `dir`.
+    pub fn compute_height(&self, dir: &Vector3<f32>, planet_seed: u32) -> f32 {
+        let seed = combine_seeds(planet_seed, self.seed ^ self.salt);
+        let dir = dir.normalize();
+
+        let mut height = 0.0;
+        for level in 0..self.level_count() {
+            let level = self.level(level, seed);
+            let cell = Vector3::new(
+                (dir.x / level.cell_size).floor() as i32,
+                (dir.y / level.cell_size).floor() as i32,
+                (dir.z / level.cell_size).floor() as i32,
+            );
+            let reach = level.reach;
+            for z in cell.z - reach..=cell.z + reach {
+                for y in cell.y - reach..=cell.y + reach {
+                    for x in cell.x - reach..=cell.x + reach {
+                        height += self.compute_cell_height(&dir, Vector3::new(x, y, z), &level);
+                    }
+                }
+            }
+        }
+
+        height
+    }
+
+    fn level_count(&self) -> usize {
+        ((self.max_radius / self.min_radius).log2().floor() as usize + 1).min(MAX_LEVELS)
+    }
+
+    /// Computes the parameters of the size class `index`
+    fn level(&self, index: usize, seed: u32) -> Level {
+        let radius = self.max_radius / (1 << index) as f32;
+        let craters_per_area = self.density / (4.0 * PI) * 2f32.powf(index as f32 * self.exponent);
+
+        // The cells are large enough to fully contain the influence of a crater, so only the
+        // direct neighbors of a cell have to be visited. Cells that would contain more than
+        // `MAX_CRATERS_PER_CELL` craters are shrunk, which requires visiting more neighbors.
+        let influence = radius * (1.0 + self.ejecta);
+        let max_cell_size = (MAX_CRATERS_PER_CELL as f32 / craters_per_area).sqrt();
+        let cell_size = (2.0 * influence).min(max_cell_size);
+
+        // A crater is moved up to half a cell when it is projected onto the sphere
+        let reach = (influence / cell_size + 0.5).ceil().max(1.0) as i32;
+
+        Level {
+            index: index as u32,
+            seed,
+            cell_size,
+            radius,
+            craters_per_cell: craters_per_area * cell_size * cell_size,
+            reach,
+        }
+    }
+
+    /// Computes the height of all craters of a single cell
+    fn compute_cell_height(&self, dir: &Vector3<f32>, cell: Vector3<i32>, level: &Level) -> f32 {
+        let cell_hash = hash(cell.x as u32 ^ hash(cell.y as u32 ^ hash(cell.z as u32)));
+        let mut state = hash(level.seed ^ hash(level.index ^ cell_hash));
+        let mut random = || {
+            state = hash(state.wrapping_add(0x9E37_79B9));
+            state as f32 / 4_294_967_296.0
+        };
+
+        // The fractional part of the expected number of craters is a probability
+        let mut count = level.craters_per_cell.floor() as u32;
+        if random() < level.craters_per_cell.fract() {
+            count += 1;
+        }
+
+        // Only guards against rounding, cells are sized to stay within the limit
+        let mut height = 0.0;
+        for _ in 0..count.min(MAX_CRATERS_PER_CELL) {
+            let center = Vector3::new(
+                (cell.x as f32 + random()) * level.cell_size,
+                (cell.y as f32 + random()) * level.cell_size,
+                (cell.z as f32 + random()) * level.cell_size,
+            );
+            let crater_radius = level.radius * (0.5 + 0.5 * random());
+
+            // Only craters close to the surface are kept, projecting them onto the sphere moves
+            // them less than half a cell.
+            let center_distance = center.norm();
+            if (center_distance - 1.0).abs() > level.cell_size * 0.5 {
+                continue;
+            }
+
+            let distance = (dir - center / center_distance).norm() / crater_radius;
+            let depth = self.depth * crater_radius / self.max_radius;
+            height += self.profile(distance) * depth;
+        }
+
+        height
+    }
+
+    /// Returns the height of a crater at `distance` from its center relative to its radius. The
+    /// result is relative to the depth of the crater.
+    fn profile(&self, distance: f32) -> f32 {
+        if distance < 1.0 {
+            // Parabolic bowl from the bottom of the crater up to the rim
+            let bowl = -1.0 + (1.0 + self.rim_height) * distance * distance;
+
+            // Smooth central peak
+            let peak_radius = 0.25;
+            let peak = if distance < peak_radius {
+                let t = 1.0 - distance / peak_radius;
+                self.peak_height * t * t * (3.0 - 2.0 * t)
+            } else {
+                0.0
+            };
+
+            bowl + peak
+        } else if distance < 1.0 + self.ejecta {
+            // The rim falls off with the ejecta blanket
+            let t = 1.0 - (distance - 1.0) / self.ejecta;
+            self.rim_height * t * t * t
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn craters(density: f32, max_radius: f32) -> Craters {
+        Craters {
+            seed: 0,
+            min_radius: 0.005,
+            max_radius,
+            exponent: default_exponent(),
+            density,
+            depth: 0.01,
+            rim_height: default_rim_height(),
+            peak_height: 0.0,
+            ejecta: default_ejecta(),
+            salt: 0,
+        }
+    }
+
+    #[test]
+    fn sparse_fields_visit_direct_neighbors() {
+        let craters = craters(20.0, 0.1);
+        for index in 0..craters.level_count() {
+            let level = craters.level(index, 0);
+            assert_eq!(level.reach, 1);
+            assert_eq!(level.cell_size, 2.0 * level.radius * (1.0 + craters.ejecta));
+        }
+    }
+
+    #[test]
+    fn dense_fields_stay_within_the_cell_limit() {
+        let craters = craters(1000.0, 0.1);
+        for index in 0..craters.level_count() {
+            let level = craters.level(index, 0);
+            assert!(level.craters_per_cell <= MAX_CRATERS_PER_CELL as f32 + 1.0e-3);
+
+            // Craters reach up to their ejecta and move up to half a cell onto the sphere
+            let influence = level.radius * (1.0 + craters.ejecta);
+            assert!(level.reach as f32 * level.cell_size >= influence + 0.5 * level.cell_size);
+        }
+        assert!(craters.level(0, 0).reach > 1);
+    }
+
+    #[test]
+    fn profile_has_a_bowl_a_rim_and_ejecta() {
+        let mut craters = craters(20.0, 0.1);
+        assert_eq!(craters.profile(0.0), -1.0);
+        assert_eq!(craters.profile(1.0), craters.rim_height);
+        assert!((craters.profile(0.999) - craters.rim_height).abs() < 0.01);
+
+        // The bowl rises and the ejecta fall off monotonically
+        for i in 0..40 {
+            let distance = i as f32 * 0.05;
+            let (near, far) = (craters.profile(distance), craters.profile(distance + 0.05));
+            if i < 20 {
+                assert!(near < far, "{} is not below {} at {}", near, far, distance);
+            } else {
+                assert!(near >= far, "{} is not above {} at {}", near, far, distance);
+            }
+        }
+
+        for &distance in [1.0 + craters.ejecta, 1.5 + craters.ejecta, 10.0].iter() {
+            assert_eq!(craters.profile(distance), 0.0);
+        }
+
+        craters.peak_height = 0.4;
+        assert!((craters.profile(0.0) + 0.6).abs() < 1.0e-6);
+        assert_eq!(
+            craters.profile(0.5),
+            -1.0 + (1.0 + craters.rim_height) * 0.25
+        );
+    }
+}
@@ -0,0 +1,582 @@
+use crate::planet::renderer::{in_range, split_distances};
+use crate::planet::{Face, GeometryProvider, PatchGeometry, PatchLocation, RendererConfig};
+use nalgebra::{Point3, Vector3, Vector4};
+use ncollide::bounding_volume::AABB3;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Fraction of the distance between two vertices within which vertices of neighboring patches
+/// are considered the same vertex.
+const WELD_FRACTION: f64 = 1.0e-3;
+
+/// A triangle mesh of (part of) the surface of a planet that can be written to common 3D file
+/// formats. Vertices on the shared edges of patches are welded, so patches of the same level
+/// form a closed surface. Positions are relative to the center of the planet.
+#[derive(Default)]
+pub struct Mesh {
+    pub positions: Vec<Point3<f64>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub colors: Vec<Vector4<f32>>,
+
+    /// Counter-clockwise triangles when seen from outside the planet
+    pub indices: Vec<u32>,
+}
+
+/// Returns the locations of all patches of the planet at `lod_level`.
+pub fn patches_at_level(lod_level: usize) -> Vec<PatchLocation> {
+    let mut locations: Vec<PatchLocation> = Face::values().map(|&face| face.into()).collect();
+    for _ in 0..lod_level {
+        locations = locations
+            .iter()
+            .flat_map(|location| {
+                vec![
+                    location.top_left(),
+                    location.top_right(),
+                    location.bottom_left(),
+                    location.bottom_right(),
+                ]
+            })
+            .collect();
+    }
+    locations
+}
+
+/// Returns the patches the `Renderer` selects for a planet of `radius` while the camera is at
+/// `camera`, relative to the center of the planet, together with their geometry. These are the
+/// patches `Renderer::resident_patches` returns once the camera stays there: starting at the
+/// faces, a patch is split as long as the camera is within the split distance of its level from
+/// its bounding box.
+pub fn patches_around(
+    provider: &GeometryProvider,
+    radius: f64,
+    camera: &Point3<f64>,
+) -> Vec<(PatchLocation, PatchGeometry)> {
+    let split_distances = split_distances(radius);
+    let mut pending: Vec<PatchLocation> = Face::values().map(|&face| face.into()).collect();
+    let mut result = Vec::new();
+    while let Some(location) = pending.pop() {
+        let geometry = provider.compute_geometry(location);
+        let (min, max) = bounds(&geometry.positions);
+        let split = location.lod_level < split_distances.len()
+            && in_range(
+                &AABB3::new(Point3::from_coordinates(min), Point3::from_coordinates(max)),
+                camera,
+                split_distances[location.lod_level],
+            );
+        if split {
+            pending.extend_from_slice(&[
+                location.top_left(),
+                location.top_right(),
+                location.bottom_left(),
+                location.bottom_right(),
+            ]);
+        } else {
+            result.push((location, geometry));
+        }
+    }
+    result
+}
+
+impl Mesh {
+    /// Generates the geometry of every patch in `locations` and combines it in a single mesh.
+    /// The skirts of the patches are left out, which leaves small gaps where patches of different
+    /// levels meet.
+    pub fn from_patches(
+        provider: &GeometryProvider,
+        config: &RendererConfig,
+        locations: &[PatchLocation],
+    ) -> Mesh {
+        let geometries: Vec<PatchGeometry> = locations
+            .iter()
+            .map(|location| provider.compute_geometry(*location))
+            .collect();
+        Mesh::from_geometries(&geometries, config)
+    }
+
+    /// Combines the geometry of patches generated with `config` in a single mesh, like
+    /// `from_patches`.
+    pub fn from_geometries(geometries: &[PatchGeometry], config: &RendererConfig) -> Mesh {
+        // The smallest patch determines how close vertices have to be to be welded
+        let weld_distance = geometries
+            .iter()
+            .map(|geometry| (geometry.positions[1] - geometry.positions[0]).norm())
+            .fold(std::f64::MAX, f64::min)
+            * WELD_FRACTION;
+
+        let mut welder = Welder::new(weld_distance);
+        let mut mesh = Mesh::default();
+        for geometry in geometries.iter() {
+            mesh.add_patch(&mut welder, geometry, config);
+        }
+        for normal in mesh.normals.iter_mut() {
+            *normal = normal.normalize();
+        }
+        mesh
+    }
+
+    fn add_patch(
+        &mut self,
+        welder: &mut Welder,
+        geometry: &PatchGeometry,
+        config: &RendererConfig,
+    ) {
+        let vertices_per_patch = config.vertices_per_patch;
+
+        let mut indices = Vec::with_capacity(vertices_per_patch * vertices_per_patch);
+        for y in 0..vertices_per_patch {
+            for x in 0..vertices_per_patch {
+                let position = geometry.positions[y * vertices_per_patch + x];
+                let normal = geometry.normals[config.vertex_normal_index(x, y)];
+                let normal = Vector3::new(normal.x as f32, normal.y as f32, normal.z as f32);
+
+                // Welded vertices get the average normal of the patches that share them
+                let index = match welder.find(&position, &self.positions) {
+                    Some(index) => {
+                        self.normals[index as usize] += normal;
+                        index
+                    }
+                    None => {
+                        let index = self.positions.len() as u32;
+                        self.positions.push(position);
+                        self.normals.push(normal);
+                        self.colors
+                            .push(geometry.colors[y * vertices_per_patch + x]);
+                        welder.insert(&position, index);
+                        index
+                    }
+                };
+                indices.push(index);
+            }
+        }
+
+        // The x axis of a patch crossed with its y axis points away from the planet
+        for y in 0..vertices_per_patch - 1 {
+            for x in 0..vertices_per_patch - 1 {
+                let top_left = indices[y * vertices_per_patch + x];
+                let top_right = indices[y * vertices_per_patch + x + 1];
+                let bottom_left = indices[(y + 1) * vertices_per_patch + x];
+                let bottom_right = indices[(y + 1) * vertices_per_patch + x + 1];
+                self.indices
+                    .extend_from_slice(&[top_left, top_right, bottom_left]);
+                self.indices
+                    .extend_from_slice(&[top_right, bottom_right, bottom_left]);
+            }
+        }
+    }
+
+    /// Writes the mesh as a Wavefront OBJ file with vertex colors after the positions.
+    pub fn write_obj<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "# {} vertices, {} triangles",
+            self.positions.len(),
+            self.indices.len() / 3
+        )?;
+        for (position, color) in self.positions.iter().zip(self.colors.iter()) {
+            writeln!(
+                writer,
+                "v {} {} {} {} {} {}",
+                position.x, position.y, position.z, color.x, color.y, color.z
+            )?;
+        }
+        for normal in self.normals.iter() {
+            writeln!(writer, "vn {} {} {}", normal.x, normal.y, normal.z)?;
+        }
+
+        // OBJ indices start at 1
+        for triangle in self.indices.chunks(3) {
+            writeln!(
+                writer,
+                "f {0}//{0} {1}//{1} {2}//{2}",
+                triangle[0] + 1,
+                triangle[1] + 1,
+                triangle[2] + 1
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the mesh as a binary little-endian PLY file.
+    pub fn write_ply<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             element vertex {}\n\
+             property float x\n\
+             property float y\n\
+             property float z\n\
+             property float nx\n\
+             property float ny\n\
+             property float nz\n\
+             property uchar red\n\
+             property uchar green\n\
+             property uchar blue\n\
+             property uchar alpha\n\
+             element face {}\n\
+             property list uchar uint vertex_indices\n\
+             end_header\n",
+            self.positions.len(),
+            self.indices.len() / 3
+        )?;
+
+        let mut vertex = Vec::with_capacity(28);
+        for i in 0..self.positions.len() {
+            let (position, normal, color) = (&self.positions[i], &self.normals[i], &self.colors[i]);
+            vertex.clear();
+            for value in &[position.x as f32, position.y as f32, position.z as f32] {
+                vertex.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in &[normal.x, normal.y, normal.z] {
+                vertex.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in &[color.x, color.y, color.z, color.w] {
+                vertex.push((value.max(0.0).min(1.0) * 255.0).round() as u8);
+            }
+            writer.write_all(&vertex)?;
+        }
+
+        for triangle in self.indices.chunks(3) {
+            writer.write_all(&[3])?;
+            for index in triangle {
+                writer.write_all(&index.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the mesh as a binary glTF 2.0 file (.glb). Single precision is not enough for
+    /// positions on the scale of a planet, so the vertices are stored relative to the center of
+    /// their bounding box which becomes the translation of the node.
+    pub fn write_gltf<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (min, max) = bounds(&self.positions);
+        let origin = (min + max) * 0.5;
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut views = Vec::new();
+        let mut add_view =
+            |buffer: &mut Vec<u8>, values: &mut Iterator<Item = [u8; 4]>, target: u32| {
+                let start = buffer.len();
+                for value in values {
+                    buffer.extend_from_slice(&value);
+                }
+                views.push(json!({
+                    "buffer": 0,
+                    "byteOffset": start,
+                    "byteLength": buffer.len() - start,
+                    "target": target,
+                }));
+            };
+
+        const ARRAY_BUFFER: u32 = 34962;
+        const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+        add_view(
+            &mut buffer,
+            &mut self
+                .positions
+                .iter()
+                .flat_map(|position| {
+                    let position = position.coords - origin;
+                    vec![position.x as f32, position.y as f32, position.z as f32]
+                })
+                .map(f32::to_le_bytes),
+            ARRAY_BUFFER,
+        );
+        add_view(
+            &mut buffer,
+            &mut self
+                .normals
+                .iter()
+                .flat_map(|normal| vec![normal.x, normal.y, normal.z])
+                .map(f32::to_le_bytes),
+            ARRAY_BUFFER,
+        );
+        add_view(
+            &mut buffer,
+            &mut self
+                .colors
+                .iter()
+                .flat_map(|color| vec![color.x, color.y, color.z, color.w])
+                .map(f32::to_le_bytes),
+            ARRAY_BUFFER,
+        );
+        add_view(
+            &mut buffer,
+            &mut self.indices.iter().map(|index| index.to_le_bytes()),
+            ELEMENT_ARRAY_BUFFER,
+        );
+
+        const FLOAT: u32 = 5126;
+        const UNSIGNED_INT: u32 = 5125;
+        let vertex_count = self.positions.len();
+        let document = json!({
+            "asset": { "version": "2.0", "generator": "omniverse" },
+            "scene": 0,
+            "scenes": [{ "nodes": [0] }],
+            "nodes": [{ "mesh": 0, "translation": [origin.x, origin.y, origin.z] }],
+            "meshes": [{
+                "primitives": [{
+                    "attributes": { "POSITION": 0, "NORMAL": 1, "COLOR_0": 2 },
+                    "indices": 3,
+                    "mode": 4,
+                }],
+            }],
+            "buffers": [{ "byteLength": buffer.len() }],
+            "bufferViews": views,
+            "accessors": [
+                {
+                    "bufferView": 0,
+                    "componentType": FLOAT,
+                    "count": vertex_count,
+                    "type": "VEC3",
+                    "min": [(min.x - origin.x) as f32, (min.y - origin.y) as f32, (min.z - origin.z) as f32],
+                    "max": [(max.x - origin.x) as f32, (max.y - origin.y) as f32, (max.z - origin.z) as f32],
+                },
+                { "bufferView": 1, "componentType": FLOAT, "count": vertex_count, "type": "VEC3" },
+                { "bufferView": 2, "componentType": FLOAT, "count": vertex_count, "type": "VEC4" },
+                { "bufferView": 3, "componentType": UNSIGNED_INT, "count": self.indices.len(), "type": "SCALAR" },
+            ],
+        });
+
+        // Both chunks have to be padded to a multiple of four bytes, the JSON with spaces
+        let mut document = serde_json::to_vec(&document)?;
+        while document.len() % 4 != 0 {
+            document.push(b' ');
+        }
+        while buffer.len() % 4 != 0 {
+            buffer.push(0);
+        }
+
+        const MAGIC: u32 = 0x4654_6C67;
+        const VERSION: u32 = 2;
+        const CHUNK_JSON: u32 = 0x4E4F_534A;
+        const CHUNK_BIN: u32 = 0x004E_4942;
+        let length = 12 + 8 + document.len() + 8 + buffer.len();
+        for value in &[
+            MAGIC,
+            VERSION,
+            length as u32,
+            document.len() as u32,
+            CHUNK_JSON,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&document)?;
+        for value in &[buffer.len() as u32, CHUNK_BIN] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&buffer)
+    }
+}
+
+/// Returns the corners of the bounding box of `positions`
+fn bounds(positions: &[Point3<f64>]) -> (Vector3<f64>, Vector3<f64>) {
+    positions.iter().fold(
+        (
+            Vector3::from_element(std::f64::MAX),
+            Vector3::from_element(std::f64::MIN),
+        ),
+        |(min, max), position| {
+            (
+                Vector3::new(
+                    min.x.min(position.x),
+                    min.y.min(position.y),
+                    min.z.min(position.z),
+                ),
+                Vector3::new(
+                    max.x.max(position.x),
+                    max.y.max(position.y),
+                    max.z.max(position.z),
+                ),
+            )
+        },
+    )
+}
+
+/// Finds previously added vertices within a small distance of a position. Vertices are hashed on
+/// a grid with cells the size of that distance, so a match is always in one of the 27 cells
+/// around the position.
+struct Welder {
+    distance: f64,
+    cells: HashMap<(i64, i64, i64), Vec<u32>>,
+}
+
+impl Welder {
+    fn new(distance: f64) -> Welder {
+        Welder {
+            distance,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: &Point3<f64>) -> (i64, i64, i64) {
+        (
+            (position.x / self.distance).floor() as i64,
+            (position.y / self.distance).floor() as i64,
+            (position.z / self.distance).floor() as i64,
+        )
+    }
+
+    fn find(&self, position: &Point3<f64>, positions: &[Point3<f64>]) -> Option<u32> {
+        let (x, y, z) = self.cell(position);
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let found = self
+                        .cells
+                        .get(&(x + dx, y + dy, z + dz))
+                        .and_then(|indices| {
+                            indices.iter().cloned().find(|&index| {
+                                (positions[index as usize] - position).norm() <= self.distance
+                            })
+                        });
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, position: &Point3<f64>, index: u32) {
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_insert_with(Vec::new).push(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planet::{Description, Generator, Precision, Terrain};
+    use std::collections::HashMap;
+
+    const RADIUS: f64 = 1000.0;
+
+    /// Returns a smooth planet with patches of 4x4 quads
+    fn generator() -> (Generator, RendererConfig) {
+        let terrain = Terrain::from_yaml("height: { constant: 0 }").unwrap();
+        let description = Description {
+            radius: RADIUS,
+            seed: 0,
+            precision: Precision::Single,
+            sea_level: None,
+        };
+        let config = RendererConfig {
+            vertices_per_patch: 5,
+            ..RendererConfig::default()
+        };
+        (Generator::new(description, terrain, config), config)
+    }
+
+    /// Returns a mesh of a smooth planet at `lod_level` with patches of 4x4 quads
+    fn mesh(lod_level: usize) -> Mesh {
+        let (generator, config) = generator();
+        Mesh::from_patches(&generator, &config, &patches_at_level(lod_level))
+    }
+
+    #[test]
+    fn triangles_face_outward() {
+        let mesh = mesh(1);
+        for triangle in mesh.indices.chunks(3) {
+            let [a, b, c] = [
+                mesh.positions[triangle[0] as usize],
+                mesh.positions[triangle[1] as usize],
+                mesh.positions[triangle[2] as usize],
+            ];
+            let normal = (b - a).cross(&(c - a));
+            assert!(normal.dot(&a.coords) > 0.0, "{:?} faces inward", triangle);
+        }
+    }
+
+    #[test]
+    fn shared_edges_are_welded() {
+        for lod_level in 0..3 {
+            let mesh = mesh(lod_level);
+
+            // A cube with n x n quads per face has 6n² + 2 corners and 12n² triangles
+            let n = 4 << lod_level;
+            assert_eq!(mesh.positions.len(), 6 * n * n + 2);
+            assert_eq!(mesh.indices.len(), 3 * 12 * n * n);
+
+            // Every edge of a closed surface is shared by exactly two triangles, once in every
+            // direction
+            let mut edges = HashMap::new();
+            for triangle in mesh.indices.chunks(3) {
+                for i in 0..3 {
+                    let edge = (triangle[i], triangle[(i + 1) % 3]);
+                    *edges.entry(edge).or_insert(0) += 1;
+                }
+            }
+            for (&(a, b), &count) in edges.iter() {
+                assert_eq!(count, 1, "edge {} {} is used {} times", a, b, count);
+                assert_eq!(edges.get(&(b, a)), Some(&1), "edge {} {} is open", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn gltf_chunks_are_aligned() {
+        let mesh = mesh(0);
+        let mut data = Vec::new();
+        mesh.write_gltf(&mut data).unwrap();
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ]) as usize
+        };
+
+        assert_eq!(&data[0..4], b"glTF");
+        assert_eq!(word(4), 2);
+        assert_eq!(word(8), data.len());
+
+        let json_length = word(12);
+        assert_eq!(&data[16..20], b"JSON");
+        assert_eq!(json_length % 4, 0);
+        let document: serde_json::Value =
+            serde_json::from_slice(&data[20..20 + json_length]).unwrap();
+
+        let bin = 20 + json_length;
+        let bin_length = word(bin);
+        assert_eq!(&data[bin + 4..bin + 8], b"BIN\0");
+        assert_eq!(bin_length % 4, 0);
+        assert_eq!(bin + 8 + bin_length, data.len());
+
+        // Every view lies within the binary chunk and starts on a multiple of four bytes
+        let buffer_length = document["buffers"][0]["byteLength"].as_u64().unwrap() as usize;
+        assert!(buffer_length <= bin_length);
+        for view in document["bufferViews"].as_array().unwrap() {
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            let length = view["byteLength"].as_u64().unwrap() as usize;
+            assert_eq!(offset % 4, 0);
+            assert!(offset + length <= buffer_length);
+        }
+        assert_eq!(
+            document["accessors"][0]["count"].as_u64(),
+            Some(mesh.positions.len() as u64)
+        );
+    }
+
+    #[test]
+    fn patches_around_the_camera_cover_the_planet() {
+        let (generator, _) = generator();
+
+        // From far away no face is split
+        let far = patches_around(&generator, RADIUS, &Point3::new(0.0, 0.0, 1.0e6));
+        assert_eq!(far.len(), 6);
+        assert!(far.iter().all(|(location, _)| location.lod_level == 0));
+
+        // On the surface the patches beneath the camera are of the last level
+        let near = patches_around(&generator, RADIUS, &Point3::new(0.0, 0.0, RADIUS));
+        let deepest = near.iter().map(|(location, _)| location.lod_level).max();
+        assert_eq!(deepest, Some(split_distances(RADIUS).len()));
+
+        // Together the patches cover all six faces exactly once
+        let area: f64 = near.iter().map(|(location, _)| location.size.powi(2)).sum();
+        assert!((area - 6.0).abs() < 1.0e-9, "the patches cover {}", area);
+    }
+}
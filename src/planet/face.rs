@@ -1,9 +1,10 @@
 #![allow(dead_code)]
 
 use crate::transform::Rotation;
+use nalgebra::Vector3;
 use std::f64::consts::PI;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Face {
     Left = 0,
     Right = 1,
@@ -41,4 +42,135 @@ impl Face {
         ];
         VALUES.iter()
     }
+
+    /// Returns the face on the other side of `edge` and the edge of that face that touches this
+    /// face.
+    pub fn neighbor(self, edge: Edge) -> (Face, Edge) {
+        NEIGHBORS[self as usize][edge as usize]
+    }
+}
+
+/// An edge of a face or of a patch within a face. The top edge is the one at offset 0 along the
+/// y axis.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Top = 0,
+    Right = 1,
+    Bottom = 2,
+    Left = 3,
+}
+
+impl Edge {
+    /// Returns the direction of the edge from the center of the face in the coordinates of the
+    /// oriented unit quad, where x and y are between -1 and 1.
+    pub fn direction(self) -> Vector3<f64> {
+        match self {
+            Edge::Top => Vector3::new(0.0, -1.0, 0.0),
+            Edge::Right => Vector3::new(1.0, 0.0, 0.0),
+            Edge::Bottom => Vector3::new(0.0, 1.0, 0.0),
+            Edge::Left => Vector3::new(-1.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn opposite(self) -> Edge {
+        match self {
+            Edge::Top => Edge::Bottom,
+            Edge::Right => Edge::Left,
+            Edge::Bottom => Edge::Top,
+            Edge::Left => Edge::Right,
+        }
+    }
+
+    /// Returns the edge closest to the direction `dir` in the coordinates of the oriented unit
+    /// quad.
+    fn from_direction(dir: &Vector3<f64>) -> Edge {
+        if dir.x.abs() > dir.y.abs() {
+            if dir.x > 0.0 {
+                Edge::Right
+            } else {
+                Edge::Left
+            }
+        } else if dir.y > 0.0 {
+            Edge::Bottom
+        } else {
+            Edge::Top
+        }
+    }
+
+    pub fn values() -> impl Iterator<Item = &'static Edge> {
+        static VALUES: [Edge; 4] = [Edge::Top, Edge::Right, Edge::Bottom, Edge::Left];
+        VALUES.iter()
+    }
+}
+
+lazy_static! {
+    /// The neighbor across every edge of every face, derived from the orientations so they can
+    /// never disagree.
+    static ref NEIGHBORS: [[(Face, Edge); 4]; 6] = {
+        let mut neighbors = [[(Face::Front, Edge::Top); 4]; 6];
+        for &face in Face::values() {
+            let normal = face.orientation() * Vector3::z();
+            for &edge in Edge::values() {
+                // The neighbor faces in the direction of the edge and its edge faces back towards
+                // the normal of this face
+                let outward = face.orientation() * edge.direction();
+                let neighbor = *Face::values()
+                    .find(|other| (other.orientation() * Vector3::z()).dot(&outward) > 0.5)
+                    .expect("Every edge has a neighboring face");
+                let back = neighbor.orientation().inverse() * normal;
+                neighbors[face as usize][edge as usize] = (neighbor, Edge::from_direction(&back));
+            }
+        }
+        neighbors
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The midpoint of `edge` of `face` on the cube
+    fn edge_center(face: Face, edge: Edge) -> Vector3<f64> {
+        face.orientation() * (Vector3::z() + edge.direction())
+    }
+
+    #[test]
+    fn neighbor_is_symmetric() {
+        for &face in Face::values() {
+            for &edge in Edge::values() {
+                let (neighbor, neighbor_edge) = face.neighbor(edge);
+                assert_eq!(neighbor.neighbor(neighbor_edge), (face, edge));
+            }
+        }
+    }
+
+    #[test]
+    fn neighbor_shares_the_edge() {
+        for &face in Face::values() {
+            let mut neighbors = Vec::new();
+            for &edge in Edge::values() {
+                let (neighbor, neighbor_edge) = face.neighbor(edge);
+                let distance =
+                    (edge_center(face, edge) - edge_center(neighbor, neighbor_edge)).norm();
+                assert!(
+                    distance < 1.0e-9,
+                    "{:?} {:?} and {:?} {:?} do not touch",
+                    face,
+                    edge,
+                    neighbor,
+                    neighbor_edge
+                );
+                neighbors.push(neighbor);
+            }
+
+            // The four neighbors are the four faces that are neither this face nor its opposite
+            let normal = face.orientation() * Vector3::z();
+            for neighbor in &neighbors {
+                assert!((neighbor.orientation() * Vector3::z()).dot(&normal).abs() < 1.0e-9);
+            }
+            for (i, neighbor) in neighbors.iter().enumerate() {
+                assert!(!neighbors[i + 1..].contains(neighbor));
+            }
+        }
+    }
 }
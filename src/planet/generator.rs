@@ -1,114 +1,269 @@
-use super::constants::{NORMALS_PER_PATCH, VERTICES_PER_PATCH};
 use crate::planet;
-use crate::planet::geometry_provider::{PatchGeometry, PatchLocation};
+use crate::planet::coordinates::{cube_to_sphere, direction};
+use crate::planet::geometry_provider::{self, PatchGeometry, PatchLocation};
 use crate::planet::GeometryProvider;
-use nalgebra::{Point3, Vector3, Point2};
+use nalgebra::{Point3, Vector3, Vector4, Point2};
 use crate::planet::Face;
-use crate::planet::TerrainLayer;
+use crate::planet::{Material, Precision, RendererConfig, Terrain, TerrainContext};
+
+/// Depth of the skirts of a patch relative to the distance between two of its vertices
+const SKIRT_DEPTH: f64 = 4.0;
+
+/// The height, normal and material of the terrain at a single point, see `Generator::surface_at`.
+#[derive(Copy, Clone, Debug)]
+pub struct SurfaceSample {
+    /// The height of the terrain above the radius of the planet
+    pub height: f64,
+    pub normal: Vector3<f64>,
+    pub material: Material,
+}
 
 #[derive(Clone)]
 pub struct Generator {
     description: planet::Description,
-    terrain: TerrainLayer,
+    terrain: Terrain,
+
+    /// Determines the resolution of the generated patches
+    config: RendererConfig,
 }
 
 impl Generator {
-    pub fn new(description: planet::Description, terrain: TerrainLayer) -> Generator {
+    /// Must be incremented whenever a change to the generator or to the evaluation of the terrain
+    /// changes the generated geometry, which invalidates the patches stored by a `PatchCache`.
+    pub const VERSION: u32 = 5;
+
+    pub fn new(description: planet::Description, terrain: Terrain, config: RendererConfig) -> Generator {
         Generator {
             description,
-            terrain
+            terrain,
+            config,
         }
     }
 
     #[inline]
     //fn compute_vertex(&self, x: f64, y: f64, patch: &PatchLocation) -> Point3<f64> {
-    fn compute_vertex(&self, oriented_position:Vector3<f64>) -> Point3<f64> {
-        let dir = morph(oriented_position);
+    fn compute_vertex(&self, oriented_position:Vector3<f64>, context: &mut TerrainContext) -> Point3<f64> {
+        let (dir, height) = self.compute_direction_and_height(oriented_position, context);
+        Point3::from_coordinates(dir * (self.description.radius + height as f64))
+    }
 
-        let dir32 = Vector3::new(dir.x as f32, dir.y as f32, dir.z as f32);
-        let height : f32 = self.terrain.compute_height(&dir32);
+    /// Returns the direction on the unit sphere and the terrain height for a position on the
+    /// oriented unit cube.
+    fn compute_direction_and_height(&self, oriented_position:Vector3<f64>, context: &mut TerrainContext) -> (Vector3<f64>, f32) {
+        let dir = cube_to_sphere(oriented_position);
 
-        Point3::from_coordinates(dir * (self.description.radius + height as f64))
+        let height : f32 = match self.description.precision {
+            Precision::Single => {
+                let dir32 = Vector3::new(dir.x as f32, dir.y as f32, dir.z as f32);
+                self.terrain.compute_height(&dir32, context)
+            },
+            Precision::Double => self.terrain.compute_height_f64(&dir, context),
+        };
+
+        (dir, height)
+    }
+
+    /// Computes the vertices for a batch of positions on the oriented unit cube at once. Returns
+    /// the directions on the unit sphere, the terrain heights and the vertex positions.
+    fn compute_vertices(&self, oriented_positions: &[Vector3<f64>], context: &mut TerrainContext) -> (Vec<Vector3<f32>>, Vec<f32>, Vec<Point3<f64>>) {
+        let dirs: Vec<Vector3<f64>> = oriented_positions.iter().map(|p| cube_to_sphere(*p)).collect();
+        let dirs32: Vec<Vector3<f32>> = dirs.iter().map(|dir| Vector3::new(dir.x as f32, dir.y as f32, dir.z as f32)).collect();
+        let heights = self.compute_heights(&dirs, &dirs32, context);
+        let positions = dirs.iter().zip(heights.iter())
+            .map(|(dir, height)| Point3::from_coordinates(dir * (self.description.radius + *height as f64)))
+            .collect();
+        (dirs32, heights, positions)
+    }
+
+    /// Computes the terrain heights for a batch of directions on the unit sphere, `dirs32` are
+    /// the same directions in single precision.
+    fn compute_heights(&self, dirs: &[Vector3<f64>], dirs32: &[Vector3<f32>], context: &mut TerrainContext) -> Vec<f32> {
+        match self.description.precision {
+            Precision::Single => self.terrain.compute_heights(dirs32, context),
+            Precision::Double => dirs.iter().map(|dir| self.terrain.compute_height_f64(dir, context)).collect(),
+        }
+    }
+
+    /// Computes the normals at a batch of positions on the oriented unit cube from the analytic
+    /// gradient of the terrain.
+    fn compute_normals(&self, oriented_positions: &[Vector3<f64>], context: &mut TerrainContext) -> Vec<Vector3<f64>> {
+        oriented_positions.iter()
+            .map(|oriented_position| self.compute_height_and_normal(&cube_to_sphere(*oriented_position), context).1)
+            .collect()
+    }
+
+    /// Computes the height and the normal of the surface in the direction `dir` on the unit
+    /// sphere. Both are evaluated in double precision.
+    fn compute_height_and_normal(&self, dir: &Vector3<f64>, context: &mut TerrainContext) -> (f32, Vector3<f64>) {
+        let (height, gradient) = self.terrain.compute_height_and_gradient(dir, context);
+        let gradient = Vector3::new(gradient.x as f64, gradient.y as f64, gradient.z as f64);
+
+        // Only the part of the gradient along the surface of the sphere tilts the normal
+        let surface_gradient = gradient - dir * gradient.dot(dir);
+        (height, (dir - surface_gradient / (self.description.radius + height as f64)).normalize())
+    }
+}
+
+/// Queries of the surface of the planet, for instance to place objects on the terrain. Latitude
+/// and longitude are in radians, see `Geodetic`. Positions are relative to the center of the
+/// planet. The terrain is always evaluated at its full detail and in double precision, like the
+/// normals of the patches, so results can differ slightly from the rendered patches.
+impl Generator {
+    pub fn description(&self) -> &planet::Description {
+        &self.description
+    }
+
+    pub fn terrain(&self) -> &Terrain {
+        &self.terrain
+    }
+
+    pub fn config(&self) -> &RendererConfig {
+        &self.config
+    }
+
+    /// Returns the height of the terrain above the radius of the planet.
+    pub fn height_at(&self, latitude: f64, longitude: f64) -> f64 {
+        self.heights_at(&[(latitude, longitude)])[0]
+    }
+
+    /// Returns the height of the terrain above the radius of the planet for every (latitude,
+    /// longitude) pair.
+    pub fn heights_at(&self, coordinates: &[(f64, f64)]) -> Vec<f64> {
+        let dirs: Vec<Vector3<f64>> = coordinates.iter()
+            .map(|(latitude, longitude)| direction(*latitude, *longitude))
+            .collect();
+        let mut context = self.terrain.context(self.description.seed);
+        self.heights_in_directions(&dirs, &mut context)
+    }
+
+    /// Returns the normal of the terrain surface.
+    pub fn normal_at(&self, latitude: f64, longitude: f64) -> Vector3<f64> {
+        self.normals_at(&[(latitude, longitude)])[0]
     }
 
-    fn compute_vertex_and_color(&self, oriented_position:Vector3<f64>) -> (Point3<f64>, Vector3<f32>) {
-        let dir = morph(oriented_position);
+    /// Returns the normal of the terrain surface for every (latitude, longitude) pair.
+    pub fn normals_at(&self, coordinates: &[(f64, f64)]) -> Vec<Vector3<f64>> {
+        let mut context = self.terrain.context(self.description.seed);
+        coordinates.iter()
+            .map(|(latitude, longitude)| self.compute_height_and_normal(&direction(*latitude, *longitude), &mut context).1)
+            .collect()
+    }
 
-        let dir32 = Vector3::new(dir.x as f32, dir.y as f32, dir.z as f32);
-        let (height, color) = self.terrain.compute_height_and_color(&dir32);
+    /// Returns the material of the terrain surface.
+    pub fn material_at(&self, latitude: f64, longitude: f64) -> Material {
+        self.materials_at(&[(latitude, longitude)])[0]
+    }
 
-        let position = Point3::from_coordinates(dir * (self.description.radius + height as f64));
+    /// Returns the material of the terrain surface for every (latitude, longitude) pair.
+    pub fn materials_at(&self, coordinates: &[(f64, f64)]) -> Vec<Material> {
+        self.surfaces_at(coordinates).iter().map(|sample| sample.material).collect()
+    }
 
-        (position, color)
+    /// Returns the height, normal and material of the terrain surface.
+    pub fn surface_at(&self, latitude: f64, longitude: f64) -> SurfaceSample {
+        self.surfaces_at(&[(latitude, longitude)])[0]
     }
 
-    fn compute_normal(&self, oriented_position:Vector3<f64>, tangent:&Vector3<f64>, binormal:&Vector3<f64>) -> Vector3<f64> {
-        let eps = 0.000001;
+    /// Returns the height, normal and material of the terrain surface for every (latitude,
+    /// longitude) pair. Cheaper than querying them separately, as the material depends on the
+    /// height and normal.
+    pub fn surfaces_at(&self, coordinates: &[(f64, f64)]) -> Vec<SurfaceSample> {
+        let mut context = self.terrain.context(self.description.seed);
+        coordinates.iter()
+            .map(|(latitude, longitude)| {
+                let dir = direction(*latitude, *longitude);
+                let (height, normal) = self.compute_height_and_normal(&dir, &mut context);
+                let dir32 = Vector3::new(dir.x as f32, dir.y as f32, dir.z as f32);
+                let normal32 = Vector3::new(normal.x as f32, normal.y as f32, normal.z as f32);
+                let material = self.terrain.compute_material(&dir32, height, &normal32, &mut context);
+                SurfaceSample { height: f64::from(height), normal, material }
+            })
+            .collect()
+    }
 
-        let px1 = self.compute_vertex(&oriented_position - tangent*eps);
-        let px2 = self.compute_vertex(&oriented_position + tangent*eps);
-        let py1 = self.compute_vertex(&oriented_position - binormal*eps);
-        let py2 = self.compute_vertex(&oriented_position + binormal*eps);
+    /// Returns the point on the terrain surface directly above or below `position`, along the
+    /// line through the center of the planet. `position` must not be the center itself.
+    pub fn surface_point_below(&self, position: &Point3<f64>) -> Point3<f64> {
+        self.surface_points_below(&[*position])[0]
+    }
 
-        let x_diff = px2 - px1;
-        let y_diff = py2 - py1;
+    /// Returns the point on the terrain surface directly above or below every position.
+    pub fn surface_points_below(&self, positions: &[Point3<f64>]) -> Vec<Point3<f64>> {
+        let dirs: Vec<Vector3<f64>> = positions.iter().map(|position| position.coords.normalize()).collect();
+        let mut context = self.terrain.context(self.description.seed);
+        let heights = self.heights_in_directions(&dirs, &mut context);
+        dirs.iter().zip(heights.iter())
+            .map(|(dir, height)| Point3::from_coordinates(dir * (self.description.radius + height)))
+            .collect()
+    }
 
-        x_diff.cross(&y_diff).normalize()
+    fn heights_in_directions(&self, dirs: &[Vector3<f64>], context: &mut TerrainContext) -> Vec<f64> {
+        dirs.iter()
+            .map(|dir| f64::from(self.terrain.compute_height_f64(dir, context)))
+            .collect()
     }
 }
 
+
 impl GeometryProvider for Generator {
     fn compute_geometry(&self, patch: PatchLocation) -> PatchGeometry {
-        // Generate vertex positions and colors
-        let vertex_step = patch.size / (VERTICES_PER_PATCH as f64 - 1.0);
-        let mut positions: Vec<Point3<f64>> =
-            Vec::with_capacity(VERTICES_PER_PATCH * VERTICES_PER_PATCH);
-        let mut colors: Vec<Vector3<f32>> =
-            Vec::with_capacity(VERTICES_PER_PATCH * VERTICES_PER_PATCH);
-
-        let corner = patch.face.orientation() * Vector3::new(patch.offset.x - 0.5, patch.offset.y - 0.5, 0.5) * 2.0;
-        let tangent = patch.face.orientation() * Vector3::new(1.0, 0.0, 0.0);
-        let binormal = patch.face.orientation() * Vector3::new(0.0, 1.0, 0.0);
-
-        for y in 0..VERTICES_PER_PATCH {
-            for x in 0..VERTICES_PER_PATCH {
-                let local_position = corner + tangent*(vertex_step * 2.0 * x as f64) + binormal*(vertex_step * 2.0 * y as f64);
-                let (position, color) = self.compute_vertex_and_color(local_position);
-                positions.push(position);
-                colors.push(color);
-            }
-        }
+        let mut context = self.terrain.context(self.description.seed);
+        let vertices_per_patch = self.config.vertices_per_patch;
+        let normals_per_patch = self.config.normals_per_patch();
+
+        // Generate vertex positions
+        let vertex_step = patch.size / (vertices_per_patch as f64 - 1.0);
+        let vertex_positions = patch.grid(vertices_per_patch, vertex_step);
+        // The footprint is the distance between two samples on the cube, which is a slight
+        // overestimation of their distance on the sphere
+        context.set_footprint((vertex_step * 2.0) as f32);
+        let (directions, heights, mut positions) = self.compute_vertices(&vertex_positions, &mut context);
 
         // Generate normals
-        let normal_step = patch.size / ((NORMALS_PER_PATCH - 2) as f64);
-        let mut normals: Vec<Vector3<f64>> =
-            Vec::with_capacity(NORMALS_PER_PATCH * NORMALS_PER_PATCH);
-        for y in 0..NORMALS_PER_PATCH {
-            for x in 0..NORMALS_PER_PATCH {
-                let local_position = corner + tangent*(normal_step * 2.0 * x as f64) + binormal*(normal_step * 2.0 * y as f64);
-                normals.push(self.compute_normal(local_position, &tangent, &binormal));
+        let normal_step = patch.size / ((normals_per_patch - 2) as f64);
+        let normal_positions = patch.grid(normals_per_patch, normal_step);
+        context.set_footprint((normal_step * 2.0) as f32);
+        let normals = self.compute_normals(&normal_positions, &mut context);
+        context.set_footprint((vertex_step * 2.0) as f32);
+
+        // Generate colors, the normal of every vertex is used to determine the slope of the terrain
+        let mut colors: Vec<Vector4<f32>> =
+            Vec::with_capacity(vertices_per_patch * vertices_per_patch);
+        let mut roughness: Vec<f32> =
+            Vec::with_capacity(vertices_per_patch * vertices_per_patch);
+        for (i, (dir, height)) in directions.iter().zip(heights.iter()).enumerate() {
+            let x = i % vertices_per_patch;
+            let y = i / vertices_per_patch;
+            let normal = &normals[self.config.vertex_normal_index(x, y)];
+            let normal = Vector3::new(normal.x as f32, normal.y as f32, normal.z as f32);
+            let material = self.terrain.compute_material(dir, *height, &normal, &mut context);
+            colors.push(material.color);
+            roughness.push(material.roughness);
+        }
+
+        // The skirt hangs below the edges of the patch towards the center of the planet and has
+        // the colors of the edges
+        if self.config.skirts {
+            let depth = vertex_step * 2.0 * self.description.radius * SKIRT_DEPTH;
+            for i in geometry_provider::skirt_source_indices(vertices_per_patch) {
+                let position = positions[i];
+                let distance = position.coords.norm();
+                positions.push(position * ((distance - depth) / distance));
+                colors.push(colors[i]);
+                roughness.push(roughness[i]);
             }
         }
 
-        PatchGeometry { positions, normals, colors }
+        PatchGeometry { positions, normals, colors, roughness }
     }
 
     fn position_at(&self, face: Face, offset: Point2<f64>) -> Point3<f64> {
-        self.compute_vertex(face.orientation() * Vector3::new(offset.x, offset.y, 0.5))
+        let mut context = self.terrain.context(self.description.seed);
+        let oriented_position = face.orientation() * Vector3::new(offset.x - 0.5, offset.y - 0.5, 0.5) * 2.0;
+        self.compute_vertex(oriented_position, &mut context)
     }
 }
 
-fn morph(pos: Vector3<f64>) -> Vector3<f64> {
-    let pos_squared = Vector3::new(pos.x * pos.x, pos.y * pos.y, pos.z * pos.z);
-    let a = Vector3::new(pos_squared.y, pos_squared.z, pos_squared.x) * 0.5;
-    let b = Vector3::new(pos_squared.z, pos_squared.x, pos_squared.y) * 0.5;
-    Vector3::new(
-        pos.x * f64::sqrt(1.0 - a.x - b.x + pos_squared.y * pos_squared.z / 3.0),
-        pos.y * f64::sqrt(1.0 - a.y - b.y + pos_squared.z * pos_squared.x / 3.0),
-        pos.z * f64::sqrt(1.0 - a.z - b.z + pos_squared.x * pos_squared.y / 3.0),
-    )
-}
-
 //#[cfg(test)]
 //mod tests {
 //    use super::*;
@@ -127,3 +282,113 @@ fn morph(pos: Vector3<f64>) -> Vector3<f64> {
 ////        });
 ////    }
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planet::patches_at_level;
+
+    #[test]
+    fn position_at_matches_the_vertices_of_patches() {
+        let terrain = Terrain::from_yaml("height: { noiseSimplex: {} }").unwrap();
+        let description = planet::Description {
+            radius: 100.0,
+            seed: 3,
+            precision: Precision::Double,
+            sea_level: None,
+        };
+        let config = RendererConfig::default();
+        let generator = Generator::new(description, terrain, config);
+
+        let last = config.vertices_per_patch - 1;
+        for patch in patches_at_level(1) {
+            let geometry = generator.compute_geometry(patch);
+            for &(x, y) in [(0, 0), (last, 0), (0, last), (last / 2, last / 2)].iter() {
+                let offset = Point2::new(
+                    patch.offset.x + patch.size * x as f64 / last as f64,
+                    patch.offset.y + patch.size * y as f64 / last as f64,
+                );
+                let expected = geometry.positions[y * config.vertices_per_patch + x];
+                let position = generator.position_at(patch.face, offset);
+                assert!(
+                    (position - expected).norm() < 1.0e-6,
+                    "{:?} instead of {:?} at {:?} of {:?}",
+                    position,
+                    expected,
+                    offset,
+                    patch
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vertices_use_the_closest_normal() {
+        let terrain = Terrain::from_yaml("height: { noiseSimplex: {} }").unwrap();
+        let description = planet::Description {
+            radius: 100.0,
+            seed: 3,
+            precision: Precision::Single,
+            sea_level: None,
+        };
+        let patch = patches_at_level(2)[5];
+        for &(vertices_per_patch, normals_resolution) in [(9, 2), (17, 4), (33, 8)].iter() {
+            let config = RendererConfig {
+                vertices_per_patch,
+                normals_resolution,
+                ..RendererConfig::default()
+            };
+            let normals_per_patch = config.normals_per_patch();
+            let vertex_step = patch.size / (vertices_per_patch - 1) as f64;
+            let normal_step = patch.size / (normals_per_patch - 2) as f64;
+            let vertices = patch.grid(vertices_per_patch, vertex_step);
+            let normals = patch.grid(normals_per_patch, normal_step);
+
+            // The grid spans two units of the cube per unit of offset
+            for y in 0..vertices_per_patch {
+                for x in 0..vertices_per_patch {
+                    let vertex = vertices[y * vertices_per_patch + x];
+                    let normal = normals[config.vertex_normal_index(x, y)];
+                    assert!((vertex - normal).norm() <= normal_step * 2f64.sqrt() + 1.0e-12);
+                }
+            }
+
+            let generator = Generator::new(description.clone(), terrain.clone(), config);
+            let geometry = generator.compute_geometry(patch);
+            assert_eq!(
+                geometry.normals.len(),
+                normals_per_patch * normals_per_patch
+            );
+            assert_eq!(geometry.colors.len(), config.patch_vertex_count());
+        }
+    }
+
+    #[test]
+    fn queries_agree_with_each_other() {
+        let terrain = Terrain::from_yaml(
+            "height: { noiseFBM: { frequency: 8, persistence: 0.5, octaves: 6 } }",
+        )
+        .unwrap();
+        let description = planet::Description {
+            radius: 100.0,
+            seed: 3,
+            precision: Precision::Single,
+            sea_level: None,
+        };
+        let generator = Generator::new(description, terrain, RendererConfig::default());
+
+        let coordinates = [(0.3, -2.0), (-1.2, 0.7), (0.0, 3.1)];
+        let heights = generator.heights_at(&coordinates);
+        let normals = generator.normals_at(&coordinates);
+        let surfaces = generator.surfaces_at(&coordinates);
+        for i in 0..coordinates.len() {
+            assert!((surfaces[i].height - heights[i]).abs() < 1.0e-6);
+            assert_eq!(surfaces[i].normal, normals[i]);
+
+            let position =
+                Point3::from_coordinates(direction(coordinates[i].0, coordinates[i].1) * 150.0);
+            let below = generator.surface_point_below(&position);
+            assert!((below.coords.norm() - (100.0 + heights[i])).abs() < 1.0e-9);
+        }
+    }
+}
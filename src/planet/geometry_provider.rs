@@ -1,5 +1,5 @@
-use crate::planet::Face;
-use nalgebra::{Point2, Point3, Vector3};
+use crate::planet::{Edge, Face};
+use nalgebra::{Point2, Point3, Vector3, Vector4};
 use crate::planet::quad_tree;
 
 /// Location of a patch in the oriented unit quad.
@@ -64,8 +64,98 @@ impl PatchLocation {
     pub fn bottom_right(&self) -> PatchLocation {
         self.split(quad_tree::Child::BottomRight)
     }
+
+    /// Returns true if the point at `offset` from the top-left corner of `face` lies within this
+    /// patch.
+    pub fn contains(&self, face: Face, offset: &Point2<f64>) -> bool {
+        self.face == face
+            && offset.x >= self.offset.x
+            && offset.y >= self.offset.y
+            && offset.x <= self.offset.x + self.size
+            && offset.y <= self.offset.y + self.size
+    }
+
+    /// Returns the child of this patch that contains the point at `offset` from the top-left
+    /// corner of the face.
+    pub fn child_containing(&self, offset: &Point2<f64>) -> quad_tree::Child {
+        let half_size = self.size * 0.5;
+        let right = offset.x >= self.offset.x + half_size;
+        let bottom = offset.y >= self.offset.y + half_size;
+        match (right, bottom) {
+            (false, false) => quad_tree::Child::TopLeft,
+            (true, false) => quad_tree::Child::TopRight,
+            (false, true) => quad_tree::Child::BottomLeft,
+            (true, true) => quad_tree::Child::BottomRight,
+        }
+    }
+
+    /// Returns the positions on the oriented unit cube of a grid of `count` by `count` points in
+    /// row-major order, starting at the top-left corner of this patch with `step` between
+    /// neighboring points.
+    pub fn grid(&self, count: usize, step: f64) -> Vec<Vector3<f64>> {
+        let corner = self.face.orientation() * Vector3::new(self.offset.x - 0.5, self.offset.y - 0.5, 0.5) * 2.0;
+        let tangent = self.face.orientation() * Vector3::new(1.0, 0.0, 0.0);
+        let binormal = self.face.orientation() * Vector3::new(0.0, 1.0, 0.0);
+
+        let mut positions = Vec::with_capacity(count * count);
+        for y in 0..count {
+            for x in 0..count {
+                positions.push(corner + tangent*(step * 2.0 * x as f64) + binormal*(step * 2.0 * y as f64));
+            }
+        }
+        positions
+    }
+
+    /// Returns the offset of the center of this patch from the top-left corner of the face.
+    pub fn center(&self) -> Point2<f64> {
+        Point2::new(self.offset.x + self.size * 0.5, self.offset.y + self.size * 0.5)
+    }
+
+    /// Returns the patch of the same size on the other side of `edge` and the edge of that patch
+    /// that touches this patch. At the border of a face the neighbor lies on the adjacent face,
+    /// which may be rotated relative to this one.
+    pub fn neighbor(&self, edge: Edge) -> (PatchLocation, Edge) {
+        let step = edge.direction() * self.size;
+        let center = self.center();
+        let center = Point2::new(center.x + step.x, center.y + step.y);
+        if center.x > 0.0 && center.x < 1.0 && center.y > 0.0 && center.y < 1.0 {
+            let offset = Point2::new(center.x - self.size * 0.5, center.y - self.size * 0.5);
+            return (self.with_offset(self.face, offset), edge.opposite());
+        }
+
+        // The center lies beyond the edge of the face on the plane of the face. Folding that
+        // plane around the edge of the cube puts the center on the neighboring face.
+        let local = Vector3::new(center.x * 2.0 - 1.0, center.y * 2.0 - 1.0, 1.0);
+        let axis = edge.direction();
+        let excess = local.dot(&axis) - 1.0;
+        let folded = local - axis * excess - Vector3::z() * excess;
+
+        let (neighbor_face, neighbor_edge) = self.face.neighbor(edge);
+        let neighbor_local =
+            neighbor_face.orientation().inverse() * (self.face.orientation() * folded);
+        let offset = Point2::new(
+            (neighbor_local.x + 1.0) * 0.5 - self.size * 0.5,
+            (neighbor_local.y + 1.0) * 0.5 - self.size * 0.5,
+        );
+        (self.with_offset(neighbor_face, offset), neighbor_edge)
+    }
+
+    /// Returns a patch at the same level at `offset` on `face`, snapped to the grid of patches of
+    /// that level to remove rounding errors.
+    fn with_offset(&self, face: Face, offset: Point2<f64>) -> PatchLocation {
+        PatchLocation {
+            face,
+            offset: Point2::new(
+                (offset.x / self.size).round() * self.size,
+                (offset.y / self.size).round() * self.size,
+            ),
+            size: self.size,
+            lod_level: self.lod_level,
+        }
+    }
 }
 
+
 impl Into<PatchLocation> for Face {
     fn into(self) -> PatchLocation {
         PatchLocation {
@@ -77,14 +167,99 @@ impl Into<PatchLocation> for Face {
     }
 }
 
-/// Geometry of a single patch
+/// Geometry of a single patch. The vertices form a square grid in row-major order. A patch with a
+/// skirt has one extra vertex for every vertex along its edges at the end of `positions`, `colors`
+/// and `roughness`, see `skirt_source_indices`.
 pub struct PatchGeometry {
     pub positions: Vec<Point3<f64>>,
     pub normals: Vec<Vector3<f64>>,
-    pub colors: Vec<Vector3<f32>>,
+    pub colors: Vec<Vector4<f32>>,
+    pub roughness: Vec<f32>,
+}
+
+/// Returns the indices of the grid vertices that the skirt vertices of a patch are extruded from in
+/// the order the skirt vertices are stored: the top row, the bottom row, the left column and the
+/// right column.
+pub fn skirt_source_indices(vertices_per_patch: usize) -> impl Iterator<Item = usize> {
+    let last = vertices_per_patch - 1;
+    let top = 0..vertices_per_patch;
+    let bottom = (0..vertices_per_patch).map(move |x| last * vertices_per_patch + x);
+    let left = (0..vertices_per_patch).map(move |y| y * vertices_per_patch);
+    let right = (0..vertices_per_patch).map(move |y| y * vertices_per_patch + last);
+    top.chain(bottom).chain(left).chain(right)
 }
 
 pub trait GeometryProvider {
     fn compute_geometry(&self, patch: PatchLocation) -> PatchGeometry;
+
+    /// Returns the position of the surface at `offset` from the top-left corner of `face`, the
+    /// offset lies between 0 and 1 like the offset of a `PatchLocation`.
     fn position_at(&self, face: Face, offset: Point2<f64>) -> Point3<f64>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planet::patches_at_level;
+
+    /// Returns the end points of `edge` of `patch` on the cube
+    fn edge_points(patch: &PatchLocation, edge: Edge) -> [Vector3<f64>; 2] {
+        let (x, y, size) = (patch.offset.x, patch.offset.y, patch.size);
+        let corners = match edge {
+            Edge::Top => [(x, y), (x + size, y)],
+            Edge::Right => [(x + size, y), (x + size, y + size)],
+            Edge::Bottom => [(x, y + size), (x + size, y + size)],
+            Edge::Left => [(x, y), (x, y + size)],
+        };
+        let cube = |(x, y): (f64, f64)| {
+            patch.face.orientation() * Vector3::new(x * 2.0 - 1.0, y * 2.0 - 1.0, 1.0)
+        };
+        [cube(corners[0]), cube(corners[1])]
+    }
+
+    fn same_location(a: &PatchLocation, b: &PatchLocation) -> bool {
+        a.face == b.face && a.lod_level == b.lod_level && a.size == b.size && a.offset == b.offset
+    }
+
+    #[test]
+    fn neighbor_is_symmetric() {
+        for lod_level in 0..4 {
+            for patch in patches_at_level(lod_level) {
+                for &edge in Edge::values() {
+                    let (neighbor, neighbor_edge) = patch.neighbor(edge);
+                    let (back, back_edge) = neighbor.neighbor(neighbor_edge);
+                    assert!(same_location(&back, &patch), "{:?} {:?}", patch, edge);
+                    assert_eq!(back_edge, edge);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn neighbor_shares_the_edge() {
+        for lod_level in 2..5 {
+            for patch in patches_at_level(lod_level) {
+                for &edge in Edge::values() {
+                    let (neighbor, neighbor_edge) = patch.neighbor(edge);
+                    assert_eq!(neighbor.lod_level, patch.lod_level);
+                    assert!(neighbor.offset.x >= 0.0 && neighbor.offset.x + neighbor.size <= 1.0);
+                    assert!(neighbor.offset.y >= 0.0 && neighbor.offset.y + neighbor.size <= 1.0);
+
+                    // The shared edge may run in the opposite direction on a rotated face
+                    let [a, b] = edge_points(&patch, edge);
+                    let [c, d] = edge_points(&neighbor, neighbor_edge);
+                    let forward = (a - c).norm().max((b - d).norm());
+                    let backward = (a - d).norm().max((b - c).norm());
+                    assert!(
+                        forward.min(backward) < 1.0e-9,
+                        "{:?} {:?} does not touch {:?} {:?}",
+                        patch,
+                        edge,
+                        neighbor,
+                        neighbor_edge
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use crate::planet::face_offset;
+use image::png::PNGDecoder;
+use image::{ColorType, DecodingResult, ImageDecoder};
+use nalgebra::Vector3;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The images a `Heightmap` is read from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HeightmapSource {
+    /// A single image that maps longitude to the horizontal axis and latitude to the vertical axis
+    Equirectangular(PathBuf),
+
+    /// One image for every face of the planet. The pixels are laid out like the patches of the
+    /// face, with the top-left pixel at offset 0 of the `PatchLocation`, so the face images baked
+    /// by `bake_maps` can be used directly.
+    CubeMap {
+        left: PathBuf,
+        right: PathBuf,
+        top: PathBuf,
+        bottom: PathBuf,
+        front: PathBuf,
+        back: PathBuf,
+    },
+}
+
+/// Determines how a `Heightmap` is sampled in between pixels.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+
+    /// Interpolates the 4 by 4 surrounding pixels with Catmull-Rom splines
+    Bicubic,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Bilinear
+    }
+}
+
+/// A grayscale image with values between 0 and 1.
+struct HeightImage {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+/// Heights read from one or more 8 or 16-bit PNG images. The images are loaded as soon as the
+/// heightmap is deserialized.
+#[derive(Clone)]
+pub struct Heightmap {
+    source: HeightmapSource,
+
+    /// One image for an equirectangular heightmap, six images indexed by `Face` for a cube map
+    images: Arc<Vec<HeightImage>>,
+}
+
+impl Heightmap {
+    pub fn load(source: HeightmapSource) -> Result<Heightmap, String> {
+        let images = match &source {
+            HeightmapSource::Equirectangular(path) => vec![HeightImage::load(path)?],
+            HeightmapSource::CubeMap {
+                left,
+                right,
+                top,
+                bottom,
+                front,
+                back,
+            } => {
+                // Ordered by the discriminant of `Face`
+                let mut images = Vec::with_capacity(6);
+                for path in [left, right, top, bottom, front, back].iter() {
+                    images.push(HeightImage::load(path)?);
+                }
+                images
+            }
+        };
+
+        Ok(Heightmap {
+            source,
+            images: Arc::new(images),
+        })
+    }
+
+    /// Samples the heightmap in the direction `dir`, the result lies between 0 and 1.
+    pub fn sample(&self, dir: &Vector3<f32>, filter: Filter) -> f32 {
+        let dir = dir.normalize();
+        match self.source {
+            HeightmapSource::Equirectangular(_) => {
+                let image = &self.images[0];
+                let latitude = dir.y.max(-1.0).min(1.0).asin();
+                let longitude = dir.x.atan2(dir.z);
+                let u = longitude / (2.0 * PI) + 0.5;
+                let v = 0.5 - latitude / PI;
+                image.sample(u, v, true, filter)
+            }
+            HeightmapSource::CubeMap { .. } => {
+                let dir = Vector3::new(f64::from(dir.x), f64::from(dir.y), f64::from(dir.z));
+                let (face, offset) = face_offset(&dir);
+                self.images[face as usize].sample(offset.x as f32, offset.y as f32, false, filter)
+            }
+        }
+    }
+}
+
+impl HeightImage {
+    fn load(path: &Path) -> Result<HeightImage, String> {
+        let error = |err: &std::fmt::Display| format!("could not read '{}': {}", path.display(), err);
+
+        let file = File::open(path).map_err(|err| error(&err))?;
+        let mut decoder = PNGDecoder::new(BufReader::new(file));
+        let (width, height) = decoder.dimensions().map_err(|err| error(&err))?;
+        let (channels, bits) = match decoder.colortype().map_err(|err| error(&err))? {
+            ColorType::Gray(bits) => (1, bits),
+            ColorType::GrayA(bits) => (2, bits),
+            ColorType::RGB(bits) => (3, bits),
+            ColorType::RGBA(bits) => (4, bits),
+            ColorType::Palette(_) => return Err(error(&"palette images are not supported")),
+        };
+
+        // Only the first channel of every pixel is used
+        let data: Vec<f32> = match decoder.read_image().map_err(|err| error(&err))? {
+            DecodingResult::U8(ref bytes) if bits == 8 => bytes
+                .iter()
+                .step_by(channels)
+                .map(|&value| f32::from(value) / 255.0)
+                .collect(),
+            DecodingResult::U8(ref bytes) if bits == 16 => bytes
+                .chunks(2)
+                .step_by(channels)
+                .map(|word| f32::from(u16::from(word[0]) << 8 | u16::from(word[1])) / 65535.0)
+                .collect(),
+            DecodingResult::U16(ref words) => words
+                .iter()
+                .step_by(channels)
+                .map(|&value| f32::from(value) / 65535.0)
+                .collect(),
+            _ => return Err(error(&format!("unsupported bit depth {}", bits))),
+        };
+
+        if data.len() != width as usize * height as usize {
+            return Err(error(&"unexpected amount of image data"));
+        }
+
+        Ok(HeightImage {
+            width: width as usize,
+            height: height as usize,
+            data,
+        })
+    }
+
+    /// Returns the pixel at the specified coordinates, the horizontal coordinate is wrapped if
+    /// `wrap` is true, otherwise it's clamped to the image. The vertical coordinate is always
+    /// clamped.
+    fn pixel(&self, x: isize, y: isize, wrap: bool) -> f32 {
+        let width = self.width as isize;
+        let x = if wrap {
+            ((x % width) + width) % width
+        } else {
+            x.max(0).min(width - 1)
+        };
+        let y = y.max(0).min(self.height as isize - 1);
+        self.data[y as usize * self.width + x as usize]
+    }
+
+    /// Samples the image at texture coordinates `u` and `v` between 0 and 1.
+    fn sample(&self, u: f32, v: f32, wrap: bool, filter: Filter) -> f32 {
+        // Pixel centers lie at half pixel offsets
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        match filter {
+            Filter::Nearest => self.pixel(x.round() as isize, y.round() as isize, wrap),
+            Filter::Bilinear => {
+                let row = |y: isize| {
+                    let a = self.pixel(x0, y, wrap);
+                    let b = self.pixel(x0 + 1, y, wrap);
+                    a + (b - a) * tx
+                };
+                let top = row(y0);
+                let bottom = row(y0 + 1);
+                top + (bottom - top) * ty
+            }
+            Filter::Bicubic => {
+                let row = |y: isize| {
+                    catmull_rom(
+                        self.pixel(x0 - 1, y, wrap),
+                        self.pixel(x0, y, wrap),
+                        self.pixel(x0 + 1, y, wrap),
+                        self.pixel(x0 + 2, y, wrap),
+                        tx,
+                    )
+                };
+                catmull_rom(row(y0 - 1), row(y0), row(y0 + 1), row(y0 + 2), ty)
+            }
+        }
+    }
+}
+
+/// Evaluates the uniform Catmull-Rom spline through `p0` to `p3`, which runs from `p1` at a `t`
+/// of 0 to `p2` at a `t` of 1 with half the difference of the neighbors of a point as its tangent
+fn catmull_rom(p0: f32, p1: f32, p2: f32, p3: f32, t: f32) -> f32 {
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+        + (3.0 * (p1 - p2) + p3 - p0) * t * t * t)
+}
+
+/// Hashes the pixels of the images rather than their paths, so a heightmap changes its hash when
+/// an image is edited.
+impl Hash for Heightmap {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for image in self.images.iter() {
+            image.width.hash(state);
+            image.height.hash(state);
+            for value in image.data.iter() {
+                value.to_bits().hash(state);
+            }
+        }
+    }
+}
+
+impl Serialize for Heightmap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Heightmap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Heightmap, D::Error> {
+        let source = HeightmapSource::deserialize(deserializer)?;
+        Heightmap::load(source).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x4 image whose pixels rise linearly to the right and have a bump in one row
+    fn image() -> HeightImage {
+        let data = (0..16)
+            .map(|i| (i % 4) as f32 * 0.25 + if i / 4 == 2 { 0.5 } else { 0.0 })
+            .collect();
+        HeightImage {
+            width: 4,
+            height: 4,
+            data,
+        }
+    }
+
+    /// The texture coordinate of the center of pixel `i` of 4
+    fn center(i: usize) -> f32 {
+        (i as f32 + 0.5) / 4.0
+    }
+
+    #[test]
+    fn bicubic_filter_passes_through_pixels() {
+        let image = image();
+        for y in 0..4 {
+            for x in 0..4 {
+                let value = image.sample(center(x), center(y), false, Filter::Bicubic);
+                assert_eq!(value, image.data[y * 4 + x]);
+            }
+        }
+    }
+
+    #[test]
+    fn bicubic_filter_interpolates_between_pixels() {
+        let image = image();
+        let between = (center(1) + center(2)) * 0.5;
+
+        // Linear runs of pixels are reproduced exactly
+        let value = image.sample(between, center(0), false, Filter::Bicubic);
+        assert!((value - 0.375).abs() < 1.0e-6);
+
+        // Halfway between two rows the neighbors are weighted by -1/16, 9/16, 9/16 and -1/16
+        let column = |y: usize| image.data[y * 4 + 1];
+        let expected = (-column(0) + 9.0 * column(1) + 9.0 * column(2) - column(3)) / 16.0;
+        let value = image.sample(center(1), between, false, Filter::Bicubic);
+        assert!((value - expected).abs() < 1.0e-6);
+    }
+}
@@ -2,20 +2,85 @@
 #[derive(Clone)]
 pub struct Description {
     pub radius: f64,
+
+    /// Seed that selects a unique variation of the terrain, the same seed always produces the
+    /// same planet
+    pub seed: u32,
+
+    /// The precision the terrain is evaluated in
+    pub precision: Precision,
+
+    /// Height of the ocean surface relative to the radius, terrain below it is covered by water.
+    /// A planet without a sea level has no oceans.
+    pub sea_level: Option<f64>,
+}
+
+/// The floating point precision the terrain of a planet is evaluated in.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Precision {
+    /// Fast, but the terrain starts to stair-step when the distance between vertices approaches a
+    /// millionth of the radius of the planet
+    Single,
+
+    /// Slower, but accurate enough to render an Earth-sized planet at walking altitude. Craters,
+    /// images, cellular noise and turbulence are still sampled in single precision.
+    Double,
+}
+
+impl Default for Precision {
+    fn default() -> Precision {
+        Precision::Single
+    }
+}
+
+/// Parses the names used on the command line, `single` or `double`.
+impl std::str::FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Precision, String> {
+        match s {
+            "single" => Ok(Precision::Single),
+            "double" => Ok(Precision::Double),
+            _ => Err(format!("unknown precision '{}', expected single or double", s)),
+        }
+    }
 }
 
-mod constants;
+mod binary;
+mod biome;
+mod color_ramp;
+mod coordinates;
+mod craters;
+mod export;
 mod face;
 mod generator;
+mod heightmap;
+mod noise;
 mod geometry_provider;
+mod patch_cache;
+mod patch_encoding;
 mod quad_tree;
 mod renderer;
+mod simd_noise;
 mod terrain;
+mod validation;
 mod async_geometry_provider;
 
-pub use self::face::Face;
-pub use self::generator::Generator;
+pub use self::biome::{Biome, BiomeSample, Climate, Condition};
+pub use self::color_ramp::{ColorRamp, ColorStop, Interpolation, Material};
+pub use self::coordinates::{
+    cube_to_sphere, direction, face_direction, face_offset, lat_lon, sphere_to_cube, Geodetic,
+};
+pub use self::craters::Craters;
+pub use self::export::{patches_around, patches_at_level, Mesh};
+pub use self::face::{Edge, Face};
+pub use self::generator::{Generator, SurfaceSample};
+pub use self::heightmap::{Filter, Heightmap, HeightmapSource};
 pub use self::geometry_provider::{GeometryProvider, PatchGeometry, PatchLocation};
-pub use self::renderer::{DrawParameters, Renderer};
-pub use self::terrain::{TerrainLayer};
+pub use self::patch_cache::PatchCache;
+pub use self::patch_encoding::EncodedPatch;
+pub use self::renderer::{DrawParameters, Renderer, RendererConfig};
+pub use self::terrain::{Terrain, TerrainContext, TerrainLayer};
+pub use self::validation::{ValidationError, ValidationErrors};
 pub use self::async_geometry_provider::{SyncGeometryProvider, ThreadpoolGeometryProvider, AsyncGeometryProvider, Token};
@@ -0,0 +1,211 @@
+use nalgebra::{Real, Vector3};
+
+/// Ken Perlin's permutation table, the same table is used by simdnoise
+#[rustfmt::skip]
+const PERM: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69,
+    142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219,
+    203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76,
+    132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173, 186,
+    3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206, 59,
+    227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163, 70,
+    221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232, 178,
+    185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162, 241, 81,
+    51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204, 176, 115,
+    121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141, 128, 195,
+    78, 66, 215, 61, 156, 180,
+];
+
+const F3: f64 = 1.0 / 3.0;
+const G3: f64 = 1.0 / 6.0;
+
+/// A floating point type noise can be computed in
+pub trait Float: Real {
+    fn from_f64(value: f64) -> Self;
+    fn to_i32(self) -> i32;
+}
+
+impl Float for f32 {
+    fn from_f64(value: f64) -> f32 {
+        value as f32
+    }
+
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+impl Float for f64 {
+    fn from_f64(value: f64) -> f64 {
+        value
+    }
+
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Computes 3D simplex noise and its gradient. In single precision the noise is identical to
+/// `simdnoise::scalar::simplex_3d`.
+pub fn simplex_3d<N: Float>(p: &Vector3<N>) -> (N, Vector3<N>) {
+    let c = N::from_f64;
+    let mut value = N::zero();
+    let mut gradient = Vector3::zeros();
+    for (d, index) in corners(p).iter() {
+        let t = c(0.5) - d.norm_squared();
+        if t < N::zero() {
+            continue;
+        }
+
+        // n = t^4 * (g . d), the derivative is t^4 * g - 8 * t^3 * (g . d) * d
+        let g = grad::<N>(*index);
+        let g_dot_d = g.dot(d);
+        let t2 = t * t;
+        let t4 = t2 * t2;
+        value += t4 * g_dot_d;
+        gradient += g * t4 - d * (c(8.0) * t2 * t * g_dot_d);
+    }
+
+    (value, gradient)
+}
+
+/// Computes 3D simplex noise like `simplex_3d` without its gradient.
+pub fn simplex_value_3d<N: Float>(p: &Vector3<N>) -> N {
+    let mut value = N::zero();
+    for (d, index) in corners(p).iter() {
+        let t = N::from_f64(0.5) - d.norm_squared();
+        if t >= N::zero() {
+            let t2 = t * t;
+            value += t2 * t2 * grad::<N>(*index).dot(d);
+        }
+    }
+    value
+}
+
+/// Returns the offsets of `p` to the four corners of the simplex containing it, each with the
+/// gradient index of the corner.
+fn corners<N: Float>(p: &Vector3<N>) -> [(Vector3<N>, i32); 4] {
+    let c = N::from_f64;
+
+    // Skew the input space to determine the simplex cell
+    let s = (p.x + p.y + p.z) * c(F3);
+    let i = (p.x + s).floor();
+    let j = (p.y + s).floor();
+    let k = (p.z + s).floor();
+    let t = (i + j + k) * c(G3);
+    let (x0, y0, z0) = (p.x - (i - t), p.y - (j - t), p.z - (k - t));
+
+    // Determine which simplex of the cell the point lies in
+    let i1 = x0 >= y0 && x0 >= z0;
+    let j1 = y0 > x0 && y0 >= z0;
+    let k1 = z0 > x0 && z0 > y0;
+    let i2 = i1 || (x0 >= y0 && x0 < z0) || (x0 >= z0 && x0 < y0);
+    let j2 = j1 || (x0 < y0 && y0 < z0) || (y0 >= z0 && x0 >= y0);
+    let k2 = k1 || (y0 < z0 && x0 >= z0) || (x0 < z0 && y0 >= z0);
+
+    let (i, j, k) = (i.to_i32(), j.to_i32(), k.to_i32());
+    let perm = |index: i32| i32::from(PERM[(index & 255) as usize]);
+
+    // The offset of the point to a corner of the simplex and the gradient index of that corner
+    let corner = |offset: (bool, bool, bool), unskew: f64| {
+        let (ox, oy, oz) = (offset.0 as i32, offset.1 as i32, offset.2 as i32);
+        let d = Vector3::new(
+            x0 - c(f64::from(ox)) + c(unskew),
+            y0 - c(f64::from(oy)) + c(unskew),
+            z0 - c(f64::from(oz)) + c(unskew),
+        );
+        (d, perm(i + ox + perm(j + oy + perm(k + oz))))
+    };
+    [
+        corner((false, false, false), 0.0),
+        corner((i1, j1, k1), G3),
+        corner((i2, j2, k2), 2.0 * G3),
+        corner((true, true, true), 3.0 * G3),
+    ]
+}
+
+/// Returns one of the 12 gradient directions of simplex noise for a hash, using the same mapping
+/// as simdnoise
+fn grad<N: Float>(hash: i32) -> Vector3<N> {
+    let h = hash & 15;
+    let (u, v) = match h {
+        0..=3 => ((1, 0, 0), (0, 1, 0)),
+        4..=7 => ((1, 0, 0), (0, 0, 1)),
+        12 | 14 => ((0, 1, 0), (1, 0, 0)),
+        _ => ((0, 1, 0), (0, 0, 1)),
+    };
+    let u_sign = if h & 1 == 0 { 1 } else { -1 };
+    let v_sign = if h & 2 == 0 { 1 } else { -1 };
+    let component = |u: i32, v: i32| N::from_f64(f64::from(u * u_sign + v * v_sign));
+    Vector3::new(
+        component(u.0, v.0),
+        component(u.1, v.1),
+        component(u.2, v.2),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points spread over several simplex cells on both sides of the origin
+    fn points() -> Vec<Vector3<f64>> {
+        (0..500)
+            .map(|i| {
+                let i = f64::from(i);
+                Vector3::new(
+                    (i * 0.731).sin() * 20.0,
+                    (i * 1.379).cos() * 20.0,
+                    (i * 0.291).sin() * (i * 0.117).cos() * 20.0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_precision_matches_simdnoise() {
+        for p in points() {
+            let p = Vector3::new(p.x as f32, p.y as f32, p.z as f32);
+            let (value, _) = simplex_3d(&p);
+            let expected = simdnoise::scalar::simplex_3d(p.x, p.y, p.z);
+            assert!(
+                (value - expected).abs() < 1.0e-6,
+                "{} instead of {} at {:?}",
+                value,
+                expected,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn gradient_matches_central_differences() {
+        let h = 1.0e-5;
+        for p in points() {
+            let (_, gradient) = simplex_3d(&p);
+            let mut expected = Vector3::zeros();
+            for axis in 0..3 {
+                let mut step = Vector3::zeros();
+                step[axis] = h;
+                expected[axis] =
+                    (simplex_3d(&(p + step)).0 - simplex_3d(&(p - step)).0) / (2.0 * h);
+            }
+            assert!(
+                (gradient - expected).norm() < 1.0e-6 * expected.norm().max(1.0),
+                "{:?} instead of {:?} at {:?}",
+                gradient,
+                expected,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn value_matches_simplex_3d() {
+        for p in points() {
+            assert_eq!(simplex_value_3d(&p), simplex_3d(&p).0);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::planet::binary::{invalid_data, Reader};
+use crate::planet::{
+    EncodedPatch, Face, Generator, GeometryProvider, PatchGeometry, PatchLocation,
+};
+use nalgebra::{Point2, Point3};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Identifies a patch file, followed by the format version
+const MAGIC: &[u8; 8] = b"OMNIPTCH";
+
+/// Must be incremented whenever the layout of a patch file changes, files with another version
+/// are regenerated.
+const VERSION: u32 = 2;
+
+/// The number of terrains whose patches are kept on disk, the patches of the least recently used
+/// terrains are removed when a cache for another terrain is constructed.
+const MAX_TERRAINS: usize = 4;
+
+/// Terrains used more recently than this are never evicted, another running instance may still be
+/// using their patches.
+const MIN_EVICTION_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Touched every time a patch of a terrain is read or written to track when it was last used
+const LAST_USED: &str = "last_used";
+
+/// Makes the names of temporary files unique within a process
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A `GeometryProvider` that stores every patch generated by a `Generator` on disk and loads it
+/// from there the next time it is requested, also across runs. Patches are keyed by a hash of the
+/// terrain including the contents of its heightmaps, `Generator::VERSION`, the parts of the
+/// description and configuration that shape the geometry and the location of the patch.
+///
+/// Patches are stored as an `EncodedPatch`. Freshly generated patches are returned exactly, only
+/// patches loaded from disk are quantized. The edge a loaded patch shares with a neighbor is off
+/// by at most half a 65535th of the height range of the patch, which the skirts hide like the
+/// cracks between levels of detail.
+///
+/// Every terrain gets its own subdirectory. Editing a terrain therefore creates a new one, only
+/// the `MAX_TERRAINS` most recently used are kept. The patches of a single terrain are not
+/// limited.
+#[derive(Clone)]
+pub struct PatchCache {
+    generator: Generator,
+
+    /// The subdirectory with the patches of this terrain
+    directory: PathBuf,
+
+    /// Hash of everything except the location that determines the geometry of a patch
+    key: u64,
+}
+
+impl PatchCache {
+    /// Constructs a cache in `directory` in front of `generator`.
+    pub fn new<P: Into<PathBuf>>(
+        generator: Generator,
+        directory: P,
+    ) -> Result<PatchCache, Box<std::error::Error>> {
+        let root = directory.into();
+        let description = generator.description();
+        let terrain = generator.terrain();
+        let config = generator.config();
+
+        // The terrain is serialized with the paths of its heightmaps, the images themselves are
+        // hashed separately
+        let mut hasher = Fnv1a::new();
+        hasher.write(&Generator::VERSION.to_le_bytes());
+        hasher.write(&serde_json::to_vec(terrain)?);
+        for heightmap in terrain.heightmaps() {
+            heightmap.hash(&mut hasher);
+        }
+        hasher.write(&description.radius.to_bits().to_le_bytes());
+        hasher.write(&description.seed.to_le_bytes());
+        hasher.write(format!("{:?}", description.precision).as_bytes());
+        hasher.write(&(config.vertices_per_patch as u64).to_le_bytes());
+        hasher.write(&(config.normals_resolution as u64).to_le_bytes());
+        hasher.write(&[config.skirts as u8]);
+        let key = hasher.finish();
+
+        let directory = root.join(format!("{:016x}", key));
+        fs::create_dir_all(&directory)?;
+        fs::write(directory.join(LAST_USED), b"")?;
+        if let Err(err) = evict(&root) {
+            log::warn!(
+                "Could not evict old patches from {}: {}",
+                root.display(),
+                err
+            );
+        }
+
+        Ok(PatchCache {
+            generator,
+            directory,
+            key,
+        })
+    }
+
+    /// Removes all patches of this terrain from the cache.
+    pub fn clear(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map_or(false, |extension| extension == "patch")
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn patch_key(&self, location: &PatchLocation) -> u64 {
+        // The offset is stored as a whole number of patches so rounding errors in the offset do
+        // not change the key
+        let mut hasher = Fnv1a::new();
+        hasher.write(&self.key.to_le_bytes());
+        hasher.write(&[location.face as u8]);
+        hasher.write(&(location.lod_level as u64).to_le_bytes());
+        hasher.write(&((location.offset.x / location.size).round() as u64).to_le_bytes());
+        hasher.write(&((location.offset.y / location.size).round() as u64).to_le_bytes());
+        hasher.finish()
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.directory.join(format!("{:016x}.patch", key))
+    }
+
+    /// Marks the terrain as used so it is not evicted by another instance
+    fn touch(&self) {
+        if let Err(err) = fs::write(self.directory.join(LAST_USED), b"") {
+            log::warn!(
+                "Could not mark {} as used: {}",
+                self.directory.display(),
+                err
+            );
+        }
+    }
+}
+
+impl GeometryProvider for PatchCache {
+    fn compute_geometry(&self, patch: PatchLocation) -> PatchGeometry {
+        let key = self.patch_key(&patch);
+        let path = self.path(key);
+        match read_patch(&path, key) {
+            Ok(Some(encoded)) => {
+                self.touch();
+                return encoded.decode();
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("Ignoring cached patch {}: {}", path.display(), err),
+        }
+
+        let geometry = self.generator.compute_geometry(patch);
+        let encoded = EncodedPatch::encode(
+            patch,
+            &geometry,
+            self.generator.description(),
+            self.generator.config(),
+        );
+        if let Err(err) = write_patch(&path, key, &encoded) {
+            log::warn!("Could not cache patch {}: {}", path.display(), err);
+        }
+        self.touch();
+        geometry
+    }
+
+    fn position_at(&self, face: Face, offset: Point2<f64>) -> Point3<f64> {
+        self.generator.position_at(face, offset)
+    }
+}
+
+/// Removes the subdirectories of all but the `MAX_TERRAINS` most recently used terrains in `root`.
+/// Terrains used within `MIN_EVICTION_AGE` are kept regardless.
+fn evict(root: &Path) -> io::Result<()> {
+    let mut terrains = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if let Ok(last_used) = fs::metadata(path.join(LAST_USED)).and_then(|m| m.modified()) {
+            terrains.push((last_used, path));
+        }
+    }
+
+    terrains.sort_by(|a, b| b.0.cmp(&a.0));
+    let now = SystemTime::now();
+    for (last_used, path) in terrains.iter().skip(MAX_TERRAINS) {
+        let age = now.duration_since(*last_used).unwrap_or_default();
+        if age >= MIN_EVICTION_AGE {
+            fs::remove_dir_all(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The 64-bit FNV-1a hash. Unlike the hashers of the standard library its output is guaranteed to
+/// stay the same between builds, which matters for keys that are stored on disk.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Writes a patch to a file: the magic bytes `OMNIPTCH`, the version as little-endian u32 and the
+/// key as little-endian u64 followed by `EncodedPatch::to_bytes`. The file is written next to its
+/// destination under a name unique to this process and call first, so neither readers nor other
+/// writers of the same patch see a partial file. The directory is created again if another
+/// instance evicted it in the meantime.
+fn write_patch(path: &Path, key: u64, patch: &EncodedPatch) -> io::Result<()> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&key.to_le_bytes());
+    data.extend_from_slice(&patch.to_bytes());
+
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let counter = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+    let temporary = path.with_extension(format!("{}.{}.tmp", std::process::id(), counter));
+    let result = fs::File::create(&temporary)
+        .and_then(|mut file| file.write_all(&data))
+        .and_then(|_| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Reads a patch written by `write_patch`. Returns `None` if the patch is not in the cache or was
+/// written by another version.
+fn read_patch(path: &Path, key: u64) -> io::Result<Option<EncodedPatch>> {
+    let mut data = Vec::new();
+    match fs::File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut reader = Reader::new(&data);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid_data("not a patch file"));
+    }
+    if reader.u32()? != VERSION {
+        return Ok(None);
+    }
+    if reader.u64()? != key {
+        return Err(invalid_data("the key does not match the file name"));
+    }
+    let remaining = reader.remaining();
+    EncodedPatch::from_bytes(reader.take(remaining)?).map(Some)
+}
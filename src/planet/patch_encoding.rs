@@ -0,0 +1,521 @@
+use crate::planet::binary::{invalid_data, Reader};
+use crate::planet::geometry_provider::skirt_source_indices;
+use crate::planet::{
+    cube_to_sphere, Description, Face, PatchGeometry, PatchLocation, RendererConfig,
+};
+use nalgebra::{Point3, Vector2, Vector3, Vector4};
+use std::io;
+
+/// Identifies an encoded patch, followed by the format version
+const MAGIC: &[u8; 8] = b"OMNIQPAT";
+
+/// Must be incremented whenever the layout of `EncodedPatch::to_bytes` changes
+const VERSION: u32 = 1;
+
+/// A lossy but compact form of a `PatchGeometry` for disk caches and transfer between
+/// processes, about a sixth of the size of the full geometry. Positions are stored as heights
+/// above the radius along the directions of the vertex grid, quantized to 16 bits between the
+/// lowest and highest vertex of the patch. Normals are octahedral encoded in 2 times 16 bits and
+/// colors and roughness use 8 bits per component. The skirt has its own height range, so its
+/// depth does not cost the precision of the surface.
+#[derive(Clone, Debug)]
+pub struct EncodedPatch {
+    pub location: PatchLocation,
+    pub radius: f64,
+    pub vertices_per_patch: usize,
+    pub normals_per_patch: usize,
+
+    /// The heights that map to 0 and 65535 for the vertices of the grid
+    pub height_range: (f64, f64),
+
+    /// The heights that map to 0 and 65535 for the vertices of the skirt
+    pub skirt_height_range: (f64, f64),
+
+    /// The heights of the grid in row-major order followed by those of the skirt
+    pub heights: Vec<u16>,
+    pub normals: Vec<[u16; 2]>,
+    pub colors: Vec<[u8; 4]>,
+    pub roughness: Vec<u8>,
+}
+
+impl EncodedPatch {
+    /// Encodes the geometry of the patch at `location`, generated for the planet `description`
+    /// with `config`.
+    pub fn encode(
+        location: PatchLocation,
+        geometry: &PatchGeometry,
+        description: &Description,
+        config: &RendererConfig,
+    ) -> EncodedPatch {
+        let grid_vertex_count = config.vertices_per_patch * config.vertices_per_patch;
+        let distances: Vec<f64> = geometry
+            .positions
+            .iter()
+            .map(|position| position.coords.norm() - description.radius)
+            .collect();
+        let (grid, skirt) = distances.split_at(grid_vertex_count);
+        let height_range = range(grid);
+        let skirt_height_range = range(skirt);
+
+        EncodedPatch {
+            location,
+            radius: description.radius,
+            vertices_per_patch: config.vertices_per_patch,
+            normals_per_patch: config.normals_per_patch(),
+            height_range,
+            skirt_height_range,
+            heights: grid
+                .iter()
+                .map(|height| quantize(*height, height_range))
+                .chain(
+                    skirt
+                        .iter()
+                        .map(|height| quantize(*height, skirt_height_range)),
+                )
+                .collect(),
+            normals: geometry.normals.iter().map(encode_octahedral).collect(),
+            colors: geometry
+                .colors
+                .iter()
+                .map(|color| {
+                    [
+                        unorm8(color.x),
+                        unorm8(color.y),
+                        unorm8(color.z),
+                        unorm8(color.w),
+                    ]
+                })
+                .collect(),
+            roughness: geometry
+                .roughness
+                .iter()
+                .map(|value| unorm8(*value))
+                .collect(),
+        }
+    }
+
+    /// Reconstructs the geometry of the patch, which can be passed to `NodeGeometry::new`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `normals_per_patch` is not a multiple of `vertices_per_patch` by a
+    /// `normals_resolution` of at least 2, which `from_bytes` rejects.
+    pub fn decode(&self) -> PatchGeometry {
+        let vertices_per_patch = self.vertices_per_patch;
+        let grid_vertex_count = vertices_per_patch * vertices_per_patch;
+        assert!(
+            valid_normal_count(vertices_per_patch, self.normals_per_patch),
+            "{} normals per patch do not match {} vertices per patch",
+            self.normals_per_patch,
+            vertices_per_patch
+        );
+
+        // The directions are computed exactly like the generator does
+        let vertex_step = self.location.size / (vertices_per_patch as f64 - 1.0);
+        let directions: Vec<Vector3<f64>> = self
+            .location
+            .grid(vertices_per_patch, vertex_step)
+            .into_iter()
+            .map(cube_to_sphere)
+            .collect();
+
+        let (grid, skirt) = self.heights.split_at(grid_vertex_count);
+        let mut positions: Vec<Point3<f64>> = directions
+            .iter()
+            .zip(grid.iter())
+            .map(|(dir, height)| {
+                Point3::from_coordinates(
+                    dir * (self.radius + dequantize(*height, self.height_range)),
+                )
+            })
+            .collect();
+        positions.extend(
+            skirt_source_indices(vertices_per_patch)
+                .zip(skirt.iter())
+                .map(|(i, height)| {
+                    Point3::from_coordinates(
+                        directions[i]
+                            * (self.radius + dequantize(*height, self.skirt_height_range)),
+                    )
+                }),
+        );
+
+        PatchGeometry {
+            positions,
+            normals: self.normals.iter().map(decode_octahedral).collect(),
+            colors: self
+                .colors
+                .iter()
+                .map(|color| {
+                    Vector4::new(
+                        f32::from(color[0]) / 255.0,
+                        f32::from(color[1]) / 255.0,
+                        f32::from(color[2]) / 255.0,
+                        f32::from(color[3]) / 255.0,
+                    )
+                })
+                .collect(),
+            roughness: self
+                .roughness
+                .iter()
+                .map(|value| f32::from(*value) / 255.0)
+                .collect(),
+        }
+    }
+
+    /// Serializes the patch to a versioned little-endian byte stream.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(
+            77 + self.heights.len() * 2
+                + self.normals.len() * 4
+                + self.colors.len() * 4
+                + self.roughness.len(),
+        );
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.push(self.location.face as u8);
+        data.extend_from_slice(&(self.location.lod_level as u32).to_le_bytes());
+        for offset in &[self.location.offset.x, self.location.offset.y] {
+            let index = (offset / self.location.size).round() as u32;
+            data.extend_from_slice(&index.to_le_bytes());
+        }
+        data.extend_from_slice(&self.radius.to_le_bytes());
+        for count in &[
+            self.vertices_per_patch,
+            self.normals_per_patch,
+            self.heights.len(),
+        ] {
+            data.extend_from_slice(&(*count as u32).to_le_bytes());
+        }
+        for value in &[
+            self.height_range.0,
+            self.height_range.1,
+            self.skirt_height_range.0,
+            self.skirt_height_range.1,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        for height in self.heights.iter() {
+            data.extend_from_slice(&height.to_le_bytes());
+        }
+        for normal in self.normals.iter() {
+            data.extend_from_slice(&normal[0].to_le_bytes());
+            data.extend_from_slice(&normal[1].to_le_bytes());
+        }
+        for color in self.colors.iter() {
+            data.extend_from_slice(color);
+        }
+        data.extend_from_slice(&self.roughness);
+        data
+    }
+
+    /// Deserializes a patch written by `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> io::Result<EncodedPatch> {
+        let mut reader = Reader::new(data);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("not an encoded patch"));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(invalid_data(&format!("unsupported version {}", version)));
+        }
+
+        let face = reader.u8()?;
+        let face = *Face::values()
+            .find(|value| **value as u8 == face)
+            .ok_or_else(|| invalid_data("invalid face"))?;
+        let lod_level = reader.u32()? as usize;
+        if lod_level > 52 {
+            return Err(invalid_data("invalid lod level"));
+        }
+        let size = 0.5f64.powi(lod_level as i32);
+        let (index_x, index_y) = (reader.u32()?, reader.u32()?);
+
+        // A face has 2^lod patches along each edge
+        let patches_per_edge = 1u64 << lod_level;
+        if u64::from(index_x) >= patches_per_edge || u64::from(index_y) >= patches_per_edge {
+            return Err(invalid_data("offset outside of the face"));
+        }
+        let mut location: PatchLocation = face.into();
+        location.offset.x = f64::from(index_x) * size;
+        location.offset.y = f64::from(index_y) * size;
+        location.size = size;
+        location.lod_level = lod_level;
+
+        let radius = reader.f64()?;
+        let vertices_per_patch = reader.u32()? as usize;
+        let normals_per_patch = reader.u32()? as usize;
+        let height_count = reader.u32()? as usize;
+        let height_range = (reader.f64()?, reader.f64()?);
+        let skirt_height_range = (reader.f64()?, reader.f64()?);
+
+        // Skirts are either absent or have one vertex for every vertex on the edges of the grid
+        let grid_vertex_count = vertices_per_patch * vertices_per_patch;
+        let normal_count = normals_per_patch * normals_per_patch;
+        if vertices_per_patch < 2
+            || (height_count != grid_vertex_count
+                && height_count != grid_vertex_count + 4 * vertices_per_patch)
+        {
+            return Err(invalid_data("invalid vertex count"));
+        }
+        if !valid_normal_count(vertices_per_patch, normals_per_patch) {
+            return Err(invalid_data("invalid normal count"));
+        }
+        if reader.remaining() != height_count * 7 + normal_count * 4 {
+            return Err(invalid_data("unexpected size"));
+        }
+
+        let mut patch = EncodedPatch {
+            location,
+            radius,
+            vertices_per_patch,
+            normals_per_patch,
+            height_range,
+            skirt_height_range,
+            heights: Vec::with_capacity(height_count),
+            normals: Vec::with_capacity(normal_count),
+            colors: Vec::with_capacity(height_count),
+            roughness: Vec::with_capacity(height_count),
+        };
+        for _ in 0..height_count {
+            patch.heights.push(reader.u16()?);
+        }
+        for _ in 0..normal_count {
+            patch.normals.push([reader.u16()?, reader.u16()?]);
+        }
+        for _ in 0..height_count {
+            let color = reader.take(4)?;
+            patch.colors.push([color[0], color[1], color[2], color[3]]);
+        }
+        patch
+            .roughness
+            .extend_from_slice(reader.take(height_count)?);
+        Ok(patch)
+    }
+}
+
+/// Returns true if `normals_per_patch` results from `RendererConfig::normals_per_patch` for
+/// `vertices_per_patch` and a valid `normals_resolution`.
+fn valid_normal_count(vertices_per_patch: usize, normals_per_patch: usize) -> bool {
+    vertices_per_patch > 0
+        && normals_per_patch % vertices_per_patch == 0
+        && normals_per_patch / vertices_per_patch >= 2
+        && (normals_per_patch / vertices_per_patch).is_power_of_two()
+}
+
+/// Returns the lowest and highest of `values`, or (0, 0) if there are none.
+fn range(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    values
+        .iter()
+        .fold((std::f64::MAX, std::f64::MIN), |(min, max), value| {
+            (min.min(*value), max.max(*value))
+        })
+}
+
+fn quantize(value: f64, (min, max): (f64, f64)) -> u16 {
+    if max <= min {
+        return 0;
+    }
+    ((value - min) / (max - min) * 65535.0).round() as u16
+}
+
+fn dequantize(value: u16, (min, max): (f64, f64)) -> f64 {
+    min + f64::from(value) / 65535.0 * (max - min)
+}
+
+fn unorm8(value: f32) -> u8 {
+    (value.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+/// Maps a unit vector onto the octahedron and unfolds the lower half over the corners of the
+/// upper half, which spreads the precision evenly over all directions.
+fn encode_octahedral(normal: &Vector3<f64>) -> [u16; 2] {
+    let projected =
+        Vector2::new(normal.x, normal.y) / (normal.x.abs() + normal.y.abs() + normal.z.abs());
+    let folded = if normal.z < 0.0 {
+        Vector2::new(
+            (1.0 - projected.y.abs()) * projected.x.signum(),
+            (1.0 - projected.x.abs()) * projected.y.signum(),
+        )
+    } else {
+        projected
+    };
+    let snorm16 = |value: f64| ((value.max(-1.0).min(1.0) * 0.5 + 0.5) * 65535.0).round() as u16;
+    [snorm16(folded.x), snorm16(folded.y)]
+}
+
+fn decode_octahedral(encoded: &[u16; 2]) -> Vector3<f64> {
+    let x = f64::from(encoded[0]) / 65535.0 * 2.0 - 1.0;
+    let y = f64::from(encoded[1]) / 65535.0 * 2.0 - 1.0;
+    let z = 1.0 - x.abs() - y.abs();
+    let (x, y) = if z < 0.0 {
+        ((1.0 - y.abs()) * x.signum(), (1.0 - x.abs()) * y.signum())
+    } else {
+        (x, y)
+    };
+    Vector3::new(x, y, z).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planet::Precision;
+
+    const RADIUS: f64 = 400_000.0;
+
+    fn description() -> Description {
+        Description {
+            radius: RADIUS,
+            seed: 0,
+            precision: Precision::Single,
+            sea_level: None,
+        }
+    }
+
+    fn location() -> PatchLocation {
+        let face: PatchLocation = Face::Top.into();
+        face.bottom_left().top_right()
+    }
+
+    /// Builds a patch on the vertex grid of the generator with bumpy heights, normals pointing
+    /// in all directions and a skirt
+    fn geometry(location: &PatchLocation, config: &RendererConfig) -> PatchGeometry {
+        let vertices_per_patch = config.vertices_per_patch;
+        let normals_per_patch = config.normals_per_patch();
+        let vertex_step = location.size / (vertices_per_patch as f64 - 1.0);
+        let mut positions: Vec<Point3<f64>> = location
+            .grid(vertices_per_patch, vertex_step)
+            .into_iter()
+            .enumerate()
+            .map(|(i, position)| {
+                let height = 2000.0 * (i as f64 * 0.37).sin();
+                Point3::from_coordinates(cube_to_sphere(position) * (RADIUS + height))
+            })
+            .collect();
+        for i in skirt_source_indices(vertices_per_patch) {
+            let position = positions[i];
+            positions.push(position * 0.995);
+        }
+
+        let vertex_count = positions.len();
+        PatchGeometry {
+            positions,
+            normals: (0..normals_per_patch * normals_per_patch)
+                .map(|i| {
+                    let i = i as f64;
+                    Vector3::new((i * 0.1).sin(), (i * 0.23).cos(), (i * 0.07).sin()).normalize()
+                })
+                .collect(),
+            colors: (0..vertex_count)
+                .map(|i| {
+                    let i = i as f32;
+                    Vector4::new((i * 0.01).fract(), (i * 0.03).fract(), 0.5, 1.0)
+                })
+                .collect(),
+            roughness: (0..vertex_count)
+                .map(|i| (i as f32 * 0.05).fract())
+                .collect(),
+        }
+    }
+
+    fn encoded() -> (PatchGeometry, EncodedPatch) {
+        let config = RendererConfig::default();
+        let geometry = geometry(&location(), &config);
+        let encoded = EncodedPatch::encode(location(), &geometry, &description(), &config);
+        (geometry, encoded)
+    }
+
+    #[test]
+    fn decode_reconstructs_the_geometry() {
+        let (geometry, encoded) = encoded();
+        let decoded = encoded.decode();
+        let grid_vertex_count = encoded.vertices_per_patch * encoded.vertices_per_patch;
+        assert_eq!(decoded.positions.len(), geometry.positions.len());
+        assert_eq!(
+            decoded.positions.len(),
+            RendererConfig::default().patch_vertex_count()
+        );
+
+        let height_step = (encoded.height_range.1 - encoded.height_range.0) / 65535.0;
+        let skirt_step = (encoded.skirt_height_range.1 - encoded.skirt_height_range.0) / 65535.0;
+        assert!(height_step > 0.0 && skirt_step > 0.0);
+        for (i, (original, decoded)) in geometry
+            .positions
+            .iter()
+            .zip(decoded.positions.iter())
+            .enumerate()
+        {
+            let step = if i < grid_vertex_count {
+                height_step
+            } else {
+                skirt_step
+            };
+            let error = (original - decoded).norm();
+            assert!(error <= step, "vertex {} is off by {}", i, error);
+        }
+
+        // The octahedral encoding is accurate to a few thousandths of a degree
+        assert_eq!(decoded.normals.len(), geometry.normals.len());
+        for (original, decoded) in geometry.normals.iter().zip(decoded.normals.iter()) {
+            let angle = original.dot(decoded).min(1.0).acos().to_degrees();
+            assert!(angle < 0.01, "normal is off by {} degrees", angle);
+        }
+
+        for (original, decoded) in geometry.colors.iter().zip(decoded.colors.iter()) {
+            assert!((original - decoded)
+                .iter()
+                .all(|component| component.abs() <= 0.5 / 255.0 + 1.0e-6));
+        }
+        for (original, decoded) in geometry.roughness.iter().zip(decoded.roughness.iter()) {
+            assert!((original - decoded).abs() <= 0.5 / 255.0 + 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let (_, encoded) = encoded();
+        let result = EncodedPatch::from_bytes(&encoded.to_bytes()).expect("valid patch");
+        assert_eq!(result.location.face, encoded.location.face);
+        assert_eq!(result.location.lod_level, encoded.location.lod_level);
+        assert_eq!(result.location.offset, encoded.location.offset);
+        assert_eq!(result.location.size, encoded.location.size);
+        assert_eq!(result.radius, encoded.radius);
+        assert_eq!(result.vertices_per_patch, encoded.vertices_per_patch);
+        assert_eq!(result.normals_per_patch, encoded.normals_per_patch);
+        assert_eq!(result.height_range, encoded.height_range);
+        assert_eq!(result.skirt_height_range, encoded.skirt_height_range);
+        assert_eq!(result.heights, encoded.heights);
+        assert_eq!(result.normals, encoded.normals);
+        assert_eq!(result.colors, encoded.colors);
+        assert_eq!(result.roughness, encoded.roughness);
+    }
+
+    #[test]
+    fn from_bytes_rejects_invalid_data() {
+        let (_, encoded) = encoded();
+        let bytes = encoded.to_bytes();
+
+        assert!(EncodedPatch::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(EncodedPatch::from_bytes(&bytes[..20]).is_err());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(EncodedPatch::from_bytes(&wrong_version).is_err());
+
+        // The patch is at level 2, which has 4 patches along an edge
+        assert_eq!(encoded.location.lod_level, 2);
+        let mut off_face = bytes.clone();
+        off_face[17..21].copy_from_slice(&4u32.to_le_bytes());
+        assert!(EncodedPatch::from_bytes(&off_face).is_err());
+
+        // The normals per patch follow the radius at bytes 25..33 and the vertices per patch
+        let normals_per_patch = (encoded.normals_per_patch as u32 + 1).to_le_bytes();
+        let mut wrong_normals = bytes.clone();
+        wrong_normals[37..41].copy_from_slice(&normals_per_patch);
+        assert!(EncodedPatch::from_bytes(&wrong_normals).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+/// Settings that trade the detail of the planet for memory and generation time. The same
+/// configuration has to be used by the `Renderer` and the `Generator` that feeds it.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RendererConfig {
+    /// The number of vertices along the edge of a patch. Must be a power of two + 1 for overlap
+    /// with the next patch.
+    pub vertices_per_patch: usize,
+
+    /// The number of normals per vertex along the edge of a patch. Must be a power of two and at
+    /// least 2.
+    pub normals_resolution: usize,
+
+    /// The maximum number of patches that can be resident on the GPU at once
+    pub max_patch_count: usize,
+
+    /// Extrude the edges of every patch downward to hide cracks between neighbouring patches
+    pub skirts: bool,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            vertices_per_patch: 32 + 1,
+            normals_resolution: 2,
+            max_patch_count: 2048,
+            skirts: true,
+        }
+    }
+}
+
+impl RendererConfig {
+    /// The number of normals along the edge of a patch
+    pub fn normals_per_patch(&self) -> usize {
+        self.vertices_per_patch * self.normals_resolution
+    }
+
+    /// The number of skirt vertices of a patch
+    pub fn skirt_vertex_count(&self) -> usize {
+        if self.skirts {
+            4 * self.vertices_per_patch
+        } else {
+            0
+        }
+    }
+
+    /// The total number of vertices of a patch including its skirt
+    pub fn patch_vertex_count(&self) -> usize {
+        self.vertices_per_patch * self.vertices_per_patch + self.skirt_vertex_count()
+    }
+
+    /// Returns the index of the normal closest to the vertex at column `x` and row `y` of a patch.
+    /// The normals cover the patch with a border of one normal on the far edges.
+    pub fn vertex_normal_index(&self, x: usize, y: usize) -> usize {
+        let normals_per_patch = self.normals_per_patch();
+        let scale = (normals_per_patch - 2) as f64 / (self.vertices_per_patch - 1) as f64;
+        let normal_x = (x as f64 * scale).round() as usize;
+        let normal_y = (y as f64 * scale).round() as usize;
+        normal_y * normals_per_patch + normal_x
+    }
+
+    /// Ensures the configuration can be used to render a planet.
+    pub fn validate(&self) -> Result<(), String> {
+        // Patches are split in four quarters that must share their middle row and column, and
+        // every vertex must be addressable by a 16-bit index.
+        let quads = self.vertices_per_patch.wrapping_sub(1);
+        if quads < 2 || !quads.is_power_of_two() || self.vertices_per_patch > 129 {
+            return Err(format!(
+                "vertices_per_patch must be a power of two + 1 between 3 and 129, not {}",
+                self.vertices_per_patch
+            ));
+        }
+        if self.normals_resolution < 2 || !self.normals_resolution.is_power_of_two() {
+            return Err(format!(
+                "normals_resolution must be a power of two and at least 2, not {}",
+                self.normals_resolution
+            ));
+        }
+
+        // The six faces of the planet are always resident
+        if self.max_patch_count < 6 {
+            return Err(format!(
+                "max_patch_count must be at least 6, not {}",
+                self.max_patch_count
+            ));
+        }
+        Ok(())
+    }
+}
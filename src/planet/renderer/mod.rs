@@ -6,14 +6,16 @@ use super::Description;
 use crate::frustum::Frustum;
 use crate::planet;
 use crate::transform::Transform;
-use nalgebra::{Matrix4, Point3, Translation3, Vector3};
+use nalgebra::{Matrix4, Point2, Point3, Translation3, Vector3};
 use std::rc::Rc;
 
+mod config;
 mod horizon_culling;
 mod node;
 mod node_backing;
 mod vertex;
 
+pub use self::config::RendererConfig;
 pub use self::node::Node;
 pub use self::vertex::Vertex;
 use crate::culling::Classify;
@@ -68,6 +70,7 @@ pub struct Renderer<T: planet::AsyncGeometryProvider + planet::GeometryProvider>
     context: Rc<Context>,
 
     description: Description,
+    config: RendererConfig,
     geometry_provider: T,
     backing: NodeBacking,
     command_buffer: RefCell<DrawCommandsIndicesBuffer>,
@@ -78,6 +81,10 @@ pub struct Renderer<T: planet::AsyncGeometryProvider + planet::GeometryProvider>
     split_distances: Vec<f64>,
 
     program: Program,
+
+    /// Draws the ocean surface on top of the terrain of the visible nodes
+    water_program: Program,
+
     index_buffer: IndexBuffer<u16>,
 
     /// Contains a mapping from streaming request id's to the QuadTree node that requested it. This
@@ -95,10 +102,11 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
     pub fn new<F: ?Sized + Facade>(
         facade: &F,
         description: Description,
+        config: RendererConfig,
         geometry_provider: T,
     ) -> Result<Renderer<T>, Box<std::error::Error>> {
-        use crate::planet::constants::{MAX_PATCH_COUNT, VERTICES_PER_PATCH};
-        use std::f64::consts::PI;
+        config.validate()?;
+        let vertices_per_patch = config.vertices_per_patch;
 
         let program = {
             let vertex_shader_src = r#"
@@ -109,7 +117,9 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
                 in vec2 position_morph_target;
                 in vec2 local_texcoords;
                 in vec2 local_texcoords_morph_target;
-                in vec3 color;
+                in vec4 color;
+                in float roughness;
+                in float skirt_depth;
 
                 in uint atlas_index;
                 in uint lod_level;
@@ -118,6 +128,8 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
 
                 out vec2 Texcoords;
                 out vec4 Color;
+                out float Roughness;
+                out vec3 ViewDirection;
                 flat out uint AtlasIndex;
                 out float MorphFactor;
                 out float LogZ;
@@ -160,7 +172,7 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
 
                 void main() {
                     // Construct the patch local coordinates and transform them to camera space
-                    vec3 pos_patch = vec3(position.xy, sample_height(local_texcoords));
+                    vec3 pos_patch = vec3(position.xy, sample_height(local_texcoords) - skirt_depth);
                     vec4 pos_camera = pose_camera*vec4(pos_patch, 1.0);
 
                     // Determine the camera distance
@@ -172,7 +184,7 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
                     vec2 morphed_position = mix(position, position_morph_target, morph_factor);
 
                     // Construct the patch local coordinates and transform them to camera space
-                    vec3 morphed_pos_patch = vec3(morphed_position, sample_height(morphed_local_texcoords));
+                    vec3 morphed_pos_patch = vec3(morphed_position, sample_height(morphed_local_texcoords) - skirt_depth);
                     vec4 morphed_pos_camera = pose_camera*vec4(morphed_pos_patch, 1.0);
 
                     // Project to the screen and apply logarithmic depth buffer
@@ -185,7 +197,9 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
                     Texcoords = morphed_local_texcoords;
                     AtlasIndex = atlas_index;
                     //Color = vec4(mix(random_colors[lod_level], random_colors[lod_level+1], morph_factor), 1);
-                    Color = vec4(color,1);
+                    Color = color;
+                    Roughness = roughness;
+                    ViewDirection = -morphed_pos_camera.xyz;
                     MorphFactor = morph_factor;
                 }
             "#;
@@ -198,6 +212,8 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
                 in vec3 Normal;
                 in vec2 Texcoords;
                 in vec4 Color;
+                in float Roughness;
+                in vec3 ViewDirection;
                 flat in uint AtlasIndex;
                 in float MorphFactor;
                 in float LogZ;
@@ -223,8 +239,120 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
                     vec3 normal_low_detail = texture2DArrayLod(normal_atlas, vec3(normal_atlas_texcoords_low_detail, AtlasIndex), 1).xyz;
                     vec3 normal = normalize(mix(normal_high_detail, normal_low_detail, MorphFactor));
 
-                    float nDotL = max(0, dot(normal, vec3(1,0,0)));
-                    color = vec4(vec3(nDotL), 1.0) * Color;
+                    vec3 light_direction = vec3(1,0,0);
+                    float nDotL = max(0, dot(normal, light_direction));
+
+                    // Blinn-Phong highlight, fully rough surfaces have no highlight at all
+                    vec3 half_vector = normalize(light_direction + normalize(ViewDirection));
+                    float shininess = mix(128.0, 1.0, Roughness);
+                    float specular = pow(max(0, dot(normal, half_vector)), shininess) * (1.0 - Roughness) * nDotL;
+
+                    color = vec4(vec3(nDotL) * Color.rgb + vec3(specular), Color.a);
+                }
+            "#;
+
+            Program::from_source(facade, vertex_shader_src, fragment_shader_src, None)?
+        };
+
+        let water_program = {
+            let vertex_shader_src = r#"
+                #version 430 core
+                #extension GL_EXT_texture_array : enable
+
+                in vec2 position;
+                in vec2 position_morph_target;
+                in vec2 local_texcoords;
+
+                in uint atlas_index;
+                in mat4 pose_camera;
+                in vec2 morph_range;
+
+                out float Depth;
+                out vec3 Normal;
+                out vec3 ViewDirection;
+                out float LogZ;
+
+                uniform mat4 view_projection;
+                uniform sampler2DArray height_atlas;
+                uniform uint vertices_per_patch;
+
+                uniform float camera_far = 20000000;
+                uniform float camera_log_z_constant = 0.01;
+
+                // The center of the planet relative to the camera
+                uniform vec3 planet_center;
+                uniform float planet_radius;
+                uniform float sea_level;
+
+                float sample_height(vec2 texcoord) {
+                    ivec2 texture_size = textureSize(height_atlas, 0).xy;
+                    vec2 texel_size = 1.0 / texture_size.xy;
+                    vec2 height_atlas_texcoords = texcoord * (vec2(1.0, 1.0) - texel_size) + texel_size*0.5;
+                    return texture2DArray(height_atlas, vec3(height_atlas_texcoords, atlas_index)).r;
+                }
+
+                void main() {
+                    // Morph the terrain exactly like the terrain shader does
+                    vec3 pos_patch = vec3(position.xy, sample_height(local_texcoords));
+                    vec4 pos_camera = pose_camera*vec4(pos_patch, 1.0);
+                    float camera_distance = length(pos_camera);
+                    float morph_factor = max(0,min(1,(camera_distance-morph_range.x)/(morph_range.y-morph_range.x)));
+                    vec2 morphed_local_texcoords = local_texcoords - fract(local_texcoords * (vertices_per_patch-1) * 0.5) * 2.0 / vertices_per_patch * morph_factor;
+                    vec2 morphed_position = mix(position, position_morph_target, morph_factor);
+                    vec3 morphed_pos_patch = vec3(morphed_position, sample_height(morphed_local_texcoords));
+                    vec3 seabed_camera = (pose_camera*vec4(morphed_pos_patch, 1.0)).xyz;
+
+                    // Project the seabed up (or down) to the ocean surface
+                    vec3 center_to_seabed = seabed_camera - planet_center;
+                    float seabed_height = length(center_to_seabed) - planet_radius;
+                    vec3 up = normalize(center_to_seabed);
+                    vec3 water_camera = planet_center + up*(planet_radius + sea_level);
+
+                    gl_Position = view_projection*vec4(water_camera, 1.0);
+                    const float far_constant = 1.0/log(camera_far*camera_log_z_constant + 1);
+                    LogZ = log(gl_Position.w*camera_log_z_constant + 1)*far_constant;
+                    gl_Position.z = (2*LogZ - 1)*gl_Position.w;
+
+                    Depth = sea_level - seabed_height;
+                    Normal = up;
+                    ViewDirection = -water_camera;
+                }
+            "#;
+
+            let fragment_shader_src = r#"
+                #version 430 core
+
+                in float Depth;
+                in vec3 Normal;
+                in vec3 ViewDirection;
+                in float LogZ;
+
+                uniform vec3 shallow_color = vec3(0.1, 0.55, 0.6);
+                uniform vec3 deep_color = vec3(0.0, 0.05, 0.3);
+
+                // The depth at which the water has mostly reached its deep color
+                uniform float depth_scale = 200.0;
+
+                out vec4 color;
+
+                void main() {
+                    // The coastline lies where the interpolated depth crosses zero
+                    if (Depth <= 0) {
+                        discard;
+                    }
+                    gl_FragDepth = LogZ;
+
+                    float deepness = 1.0 - exp(-Depth / depth_scale);
+                    vec3 water_color = mix(shallow_color, deep_color, deepness);
+                    float alpha = mix(0.3, 0.95, deepness);
+
+                    vec3 normal = normalize(Normal);
+                    vec3 light_direction = vec3(1,0,0);
+                    float nDotL = max(0, dot(normal, light_direction));
+                    vec3 half_vector = normalize(light_direction + normalize(ViewDirection));
+                    float specular = pow(max(0, dot(normal, half_vector)), 128.0) * nDotL;
+
+                    color = vec4(vec3(nDotL) * water_color + vec3(specular), alpha);
                 }
             "#;
 
@@ -232,82 +360,117 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
         };
 
         let index_buffer = {
-            let mut indices: Vec<u16> =
-                Vec::with_capacity((VERTICES_PER_PATCH - 1) * (VERTICES_PER_PATCH - 1) * 6);
+            let last = vertices_per_patch - 1;
+            let grid_vertex_count = vertices_per_patch * vertices_per_patch;
+            let mut indices: Vec<u16> = Vec::with_capacity(
+                (last * last + if config.skirts { 4 * last } else { 0 }) * 6,
+            );
             {
+                // Adds a quad between the vertices `a` and `a + 1` along an edge and the skirt
+                // vertices below them. The skirts of the top and right edges are wound the other
+                // way around than those of the bottom and left edges so all of them face outward.
+                let skirt_sources: Vec<usize> =
+                    planet::geometry_provider::skirt_source_indices(vertices_per_patch).collect();
+                let add_skirt = |indices: &mut Vec<u16>, edge: usize, a: usize, flip: bool| {
+                    let skirt = edge * vertices_per_patch + a;
+                    let (edge_a, edge_b) = (skirt_sources[skirt], skirt_sources[skirt + 1]);
+                    let skirt_a = grid_vertex_count + skirt;
+                    let skirt_b = skirt_a + 1;
+                    let quad = if flip {
+                        [edge_a, edge_b, skirt_b, edge_a, skirt_b, skirt_a]
+                    } else {
+                        [edge_a, skirt_b, edge_b, edge_a, skirt_a, skirt_b]
+                    };
+                    indices.extend(quad.iter().map(|&i| i as u16));
+                };
+
                 let mut add_region =
                     |x_start: usize, y_start: usize, x_end: usize, y_end: usize| {
                         for y in y_start..y_end - 1 {
                             for x in x_start..x_end - 1 {
-                                indices.push(((x + 0) + (y + 0) * VERTICES_PER_PATCH) as u16);
-                                indices.push(((x + 0) + (y + 1) * VERTICES_PER_PATCH) as u16);
-                                indices.push(((x + 1) + (y + 1) * VERTICES_PER_PATCH) as u16);
-                                indices.push(((x + 0) + (y + 0) * VERTICES_PER_PATCH) as u16);
-                                indices.push(((x + 1) + (y + 1) * VERTICES_PER_PATCH) as u16);
-                                indices.push(((x + 1) + (y + 0) * VERTICES_PER_PATCH) as u16);
+                                indices.push(((x + 0) + (y + 0) * vertices_per_patch) as u16);
+                                indices.push(((x + 0) + (y + 1) * vertices_per_patch) as u16);
+                                indices.push(((x + 1) + (y + 1) * vertices_per_patch) as u16);
+                                indices.push(((x + 0) + (y + 0) * vertices_per_patch) as u16);
+                                indices.push(((x + 1) + (y + 1) * vertices_per_patch) as u16);
+                                indices.push(((x + 1) + (y + 0) * vertices_per_patch) as u16);
+                            }
+                        }
+
+                        // The skirts along the outer edges of this region are part of the region
+                        // so every quarter of a patch can still be drawn on its own
+                        if config.skirts {
+                            for x in x_start..x_end - 1 {
+                                if y_start == 0 {
+                                    add_skirt(&mut indices, 0, x, true);
+                                }
+                                if y_end == vertices_per_patch {
+                                    add_skirt(&mut indices, 1, x, false);
+                                }
+                            }
+                            for y in y_start..y_end - 1 {
+                                if x_start == 0 {
+                                    add_skirt(&mut indices, 2, y, false);
+                                }
+                                if x_end == vertices_per_patch {
+                                    add_skirt(&mut indices, 3, y, true);
+                                }
                             }
                         }
                     };
 
-                add_region(0, 0, VERTICES_PER_PATCH / 2 + 1, VERTICES_PER_PATCH / 2 + 1);
+                add_region(0, 0, vertices_per_patch / 2 + 1, vertices_per_patch / 2 + 1);
 
                 add_region(
-                    VERTICES_PER_PATCH / 2,
+                    vertices_per_patch / 2,
                     0,
-                    VERTICES_PER_PATCH,
-                    VERTICES_PER_PATCH / 2 + 1,
+                    vertices_per_patch,
+                    vertices_per_patch / 2 + 1,
                 );
                 add_region(
                     0,
-                    VERTICES_PER_PATCH / 2,
-                    VERTICES_PER_PATCH / 2 + 1,
-                    VERTICES_PER_PATCH,
+                    vertices_per_patch / 2,
+                    vertices_per_patch / 2 + 1,
+                    vertices_per_patch,
                 );
                 add_region(
-                    VERTICES_PER_PATCH / 2,
-                    VERTICES_PER_PATCH / 2,
-                    VERTICES_PER_PATCH,
-                    VERTICES_PER_PATCH,
+                    vertices_per_patch / 2,
+                    vertices_per_patch / 2,
+                    vertices_per_patch,
+                    vertices_per_patch,
                 );
             }
             IndexBuffer::new(facade, PrimitiveType::TrianglesList, &indices)?
         };
 
-        let max_lod_level = ((0.5 * PI * description.radius).log2().ceil() - 1.0).max(1.0) as usize;
+        let split_distances = split_distances(description.radius);
+        let max_lod_level = split_distances.len() - 1;
 
-        let mut split_distances: Vec<f64> = Vec::with_capacity(max_lod_level);
-        split_distances.push(1.0);
-        let mut last_value = 2.0;
-        for _i in 0..max_lod_level {
-            let split_amount = 2.0;
-            split_distances.push(last_value * split_amount);
-            last_value *= split_amount;
-        }
-        split_distances.reverse();
-
-        let mut backing = NodeBacking::new(facade)?;
+        let mut backing = NodeBacking::new(facade, config)?;
 
         fn generate_face(
             backing: &mut NodeBacking,
             face: planet::Face,
             geometry_provider: &planet::GeometryProvider,
+            description: &Description,
         ) -> Face {
             Face {
                 face,
                 root: QuadTree::new(Node::WithGeometry(NodeGeometry::new(
                     backing,
                     &geometry_provider.compute_geometry(face.into()),
+                    description,
                 ))),
             }
         }
 
         let faces = [
-            generate_face(&mut backing, planet::Face::Front, &geometry_provider),
-            generate_face(&mut backing, planet::Face::Back, &geometry_provider),
-            generate_face(&mut backing, planet::Face::Left, &geometry_provider),
-            generate_face(&mut backing, planet::Face::Right, &geometry_provider),
-            generate_face(&mut backing, planet::Face::Top, &geometry_provider),
-            generate_face(&mut backing, planet::Face::Bottom, &geometry_provider),
+            generate_face(&mut backing, planet::Face::Front, &geometry_provider, &description),
+            generate_face(&mut backing, planet::Face::Back, &geometry_provider, &description),
+            generate_face(&mut backing, planet::Face::Left, &geometry_provider, &description),
+            generate_face(&mut backing, planet::Face::Right, &geometry_provider, &description),
+            generate_face(&mut backing, planet::Face::Top, &geometry_provider, &description),
+            generate_face(&mut backing, planet::Face::Bottom, &geometry_provider, &description),
         ];
 
         Ok(Renderer {
@@ -316,17 +479,19 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
             backing,
             geometry_provider,
             description,
+            config,
             program,
+            water_program,
             index_buffer,
             max_lod_level,
             split_distances,
             per_visible_node_buffer: RefCell::new(VertexBuffer::empty_persistent(
                 facade,
-                MAX_PATCH_COUNT,
+                config.max_patch_count,
             )?),
             command_buffer: RefCell::new(DrawCommandsIndicesBuffer::empty_persistent(
                 facade,
-                MAX_PATCH_COUNT,
+                config.max_patch_count,
             )?),
             pending_geometry_requests: PendingStreamingNodesMap::new(),
         })
@@ -355,10 +520,17 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
             frustum_planet.transform.rotation,
         ));
 
-        // Construct the cone for horizon culling
+        // Construct the cone for horizon culling, the ocean surface occludes as well but only if it
+        // lies below the radius of the planet
+        let occluder_radius = self
+            .description
+            .sea_level
+            .map_or(self.description.radius, |sea_level| {
+                self.description.radius + sea_level.min(0.0)
+            });
         let horizon_cone = horizon_culling::Cone::new(
             Point3::from_coordinates(frustum_planet.transform.translation.vector),
-            self.description.radius,
+            occluder_radius,
         );
 
         // Query all faces for visible nodes
@@ -374,7 +546,7 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
         // Setup all uniforms for drawing
         let uniforms = uniform! {
             view_projection: Into::<[[f32; 4]; 4]>::into(projection_frustum.view_projection),
-            vertices_per_patch: planet::constants::VERTICES_PER_PATCH as u32,
+            vertices_per_patch: self.config.vertices_per_patch as u32,
             height_atlas: self.backing.heights.texture.sampled()
                 .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear),
             normal_atlas: self.backing.normals.texture.sampled()
@@ -464,6 +636,50 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
                 &params,
             )
             .unwrap();
+
+        // Draw the ocean surface on top of the terrain of the same nodes
+        if let Some(sea_level) = self.description.sea_level {
+            let planet_center = -frustum_planet.transform.translation.vector;
+            let water_uniforms = uniform! {
+                view_projection: Into::<[[f32; 4]; 4]>::into(projection_frustum.view_projection),
+                vertices_per_patch: self.config.vertices_per_patch as u32,
+                height_atlas: self.backing.heights.texture.sampled()
+                    .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear),
+                camera_far: frustum.far_distance,
+                planet_center: [planet_center.x as f32, planet_center.y as f32, planet_center.z as f32],
+                planet_radius: self.description.radius as f32,
+                sea_level: sea_level as f32,
+            };
+
+            // The terrain below the surface shows through, so the water does not write depth
+            let water_params = glium::DrawParameters {
+                depth: glium::Depth {
+                    test: glium::draw_parameters::DepthTest::IfLess,
+                    write: false,
+                    ..Default::default()
+                },
+                blend: glium::Blend::alpha_blending(),
+                ..params
+            };
+
+            frame
+                .draw(
+                    (
+                        &self.backing.vertices.vertex_buffer,
+                        node_instance_data.per_instance().unwrap(),
+                    ),
+                    IndicesSource::MultidrawElement {
+                        commands: command_buffer_slice.as_slice_any(),
+                        indices: self.index_buffer.as_slice_any(),
+                        data_type: self.index_buffer.get_indices_type(),
+                        primitives: self.index_buffer.get_primitives_type(),
+                    },
+                    &self.water_program,
+                    &water_uniforms,
+                    &water_params,
+                )
+                .unwrap();
+        }
     }
 
     /// Ensures that all the nodes in range of the frustum are either in a pending state or contain
@@ -493,9 +709,11 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
         // Process streaming results
         let backing = &mut self.backing;
         let pending_requests = &mut self.pending_geometry_requests;
+        let description = &self.description;
         self.geometry_provider.receive_all(|id, data| {
             if let Some(node) = pending_requests.get(&id) {
-                let node_geometry = Node::WithGeometry(NodeGeometry::new(backing, &data));
+                let node_geometry =
+                    Node::WithGeometry(NodeGeometry::new(backing, &data, description));
                 unsafe {
                     (**node).content = node_geometry;
                 }
@@ -515,10 +733,84 @@ impl<T: planet::AsyncGeometryProvider + planet::GeometryProvider> Renderer<T> {
             face.root = QuadTree::new(Node::WithGeometry(NodeGeometry::new(
                 &mut self.backing,
                 &self.geometry_provider.compute_geometry(face.face.into()),
+                &self.description,
             )));
         }
     }
 
+    /// Returns the location of the most detailed patch with resident geometry that contains the
+    /// point in the direction of `position` relative to the center of the planet. There always is
+    /// one, as the roots of the faces are resident from the start. `position` must not be the
+    /// center itself.
+    pub fn resident_patch_at(&self, position: &Point3<f64>) -> PatchLocation {
+        let (face, offset) = planet::face_offset(&position.coords);
+        self.deepest_resident_patch(face, &offset, std::usize::MAX)
+    }
+
+    /// Returns the patch on the other side of `edge` of `location` at the same level, or the
+    /// nearest coarser level if that patch is not resident, and the edge of that patch that
+    /// faces `location`. Neighbors on other faces are found across the seams of the cube. The
+    /// coarser patch can be an ancestor of `location` when its children are only partly resident.
+    pub fn resident_neighbor(&self, location: &PatchLocation, edge: planet::Edge) -> (PatchLocation, planet::Edge) {
+        let (neighbor, neighbor_edge) = location.neighbor(edge);
+        let resident = self.deepest_resident_patch(neighbor.face, &neighbor.center(), neighbor.lod_level);
+        (resident, neighbor_edge)
+    }
+
+    /// Returns the most detailed patch with resident geometry at `offset` on `face` that is not
+    /// deeper than `max_lod_level`.
+    fn deepest_resident_patch(&self, face: planet::Face, offset: &Point2<f64>, max_lod_level: usize) -> PatchLocation {
+        let root = self.faces.iter()
+            .find(|f| f.face == face)
+            .expect("Every face has a root");
+
+        // The roots of the faces always have geometry
+        let mut node = &root.root;
+        let mut location: PatchLocation = face.into();
+        while let Some(ref children) = node.children {
+            if location.lod_level >= max_lod_level {
+                break;
+            }
+            let child = location.child_containing(offset);
+            let child_node = &children[child.index()];
+            if let Node::Pending(..) = child_node.content {
+                break;
+            }
+            node = child_node;
+            location = location.split(child);
+        }
+        location
+    }
+
+    /// Returns the most detailed resident patches that together cover the entire planet, these
+    /// are the levels of detail selected for the positions of the camera so far. Use
+    /// `planet::Mesh::from_patches` to export them.
+    pub fn resident_patches(&self) -> Vec<PatchLocation> {
+        fn collect(node: &QuadTree<Node>, location: PatchLocation, result: &mut Vec<PatchLocation>) {
+            // A node is only replaced by its children once all of them are resident
+            let children = node.children.as_ref().filter(|children| {
+                children.iter().all(|child| match child.content {
+                    Node::WithGeometry(..) => true,
+                    Node::Pending(..) => false,
+                })
+            });
+            match children {
+                Some(children) => {
+                    for &child in quad_tree::Child::values() {
+                        collect(&children[child.index()], location.split(child), result);
+                    }
+                }
+                None => result.push(location),
+            }
+        }
+
+        let mut result = Vec::new();
+        for face in self.faces.iter() {
+            collect(&face.root, face.face.into(), &mut result);
+        }
+        result
+    }
+
     /// Returns the context corresponding to this Renderer.
     pub fn get_context(&self) -> &Rc<Context> {
         &self.context
@@ -862,9 +1154,29 @@ impl<'a> LODSelectHelper<'a> {
     }
 }
 
+/// Returns for every level of detail the distance to the camera within which the patches of that
+/// level are split into four patches of the next level. Patches of the level after the last are
+/// never split.
+pub fn split_distances(radius: f64) -> Vec<f64> {
+    use std::f64::consts::PI;
+
+    let max_lod_level = ((0.5 * PI * radius).log2().ceil() - 1.0).max(1.0) as usize;
+
+    let mut split_distances: Vec<f64> = Vec::with_capacity(max_lod_level);
+    split_distances.push(1.0);
+    let mut last_value = 2.0;
+    for _i in 0..max_lod_level {
+        let split_amount = 2.0;
+        split_distances.push(last_value * split_amount);
+        last_value *= split_amount;
+    }
+    split_distances.reverse();
+    split_distances
+}
+
 /// Performs a AABB circle collision check to see if the AABB is within a certain distance of a
 /// point.
-fn in_range(
+pub fn in_range(
     aabb: &ncollide::bounding_volume::AABB3<f64>,
     position: &Point3<f64>,
     range: f64,
@@ -3,7 +3,6 @@ use crate::planet;
 use crate::planet::quad_tree::HasAABB;
 use crate::planet::renderer::node_backing::NodeBacking;
 use crate::planet::renderer::node_backing::NodeId;
-use core::mem;
 use nalgebra::{Matrix4, Point3, UnitQuaternion};
 use ncollide::bounding_volume::{AABB, AABB3};
 use std::sync::Arc;
@@ -23,8 +22,16 @@ pub struct NodeGeometry {
 }
 
 impl NodeGeometry {
-    pub fn new(backing: &mut NodeBacking, geometry: &planet::PatchGeometry) -> NodeGeometry {
-        use crate::planet::constants::{NORMALS_PER_PATCH, VERTICES_PER_PATCH};
+    pub fn new(
+        backing: &mut NodeBacking,
+        geometry: &planet::PatchGeometry,
+        description: &planet::Description,
+    ) -> NodeGeometry {
+        let vertices_per_patch = backing.config.vertices_per_patch;
+        let grid_vertex_count = vertices_per_patch * vertices_per_patch;
+        let normals_per_patch = backing.config.normals_per_patch();
+        assert_eq!(geometry.positions.len(), backing.config.patch_vertex_count());
+        assert_eq!(geometry.normals.len(), normals_per_patch * normals_per_patch);
 
         let id = backing.acquire();
 
@@ -34,9 +41,9 @@ impl NodeGeometry {
         // Compute the reference frame of the node
         let origin = geometry.positions[0];
         let tangent =
-            (geometry.positions[VERTICES_PER_PATCH - 1] - geometry.positions[0]).normalize();
+            (geometry.positions[vertices_per_patch - 1] - geometry.positions[0]).normalize();
         let geometric_binormal = (&geometry.positions
-            [(VERTICES_PER_PATCH * VERTICES_PER_PATCH) - 1]
+            [(vertices_per_patch * vertices_per_patch) - 1]
             - &geometry.positions[0])
             .normalize();
         let normal = tangent.cross(&geometric_binormal).normalize();
@@ -44,50 +51,84 @@ impl NodeGeometry {
         let transform = UnitQuaternion::new_observer_frame(&normal, &binormal);
         let inverse_transform = transform.inverse();
 
-        let mut heights: [f32; VERTICES_PER_PATCH * VERTICES_PER_PATCH];
-        let mut vertices: [Vertex; VERTICES_PER_PATCH * VERTICES_PER_PATCH];
-        let mut normals: [(f32, f32, f32); NORMALS_PER_PATCH * NORMALS_PER_PATCH];
-        let mut normals_low_detail: [(f32, f32, f32);
-            (NORMALS_PER_PATCH / 2) * (NORMALS_PER_PATCH / 2)];
-
-        unsafe {
-            heights = mem::uninitialized();
-            vertices = mem::uninitialized();
-            normals = mem::uninitialized();
-            normals_low_detail = mem::uninitialized();
-
-            for (i, pos) in geometry.positions.iter().enumerate() {
-                min = nalgebra::inf(&min, pos);
-                max = nalgebra::sup(&max, pos);
-
-                let x = i % VERTICES_PER_PATCH;
-                let y = (i - x) / VERTICES_PER_PATCH;
-
-                // Compute the vertex index that this vertex will morph to while morphing
-                let morph_target_index = i - ((x % 2) * 1) - ((y % 2) * VERTICES_PER_PATCH);
-
-                let rel_pos = inverse_transform * (pos - origin);
-                heights[i] = rel_pos.z as f32;
-
-                vertices[i].position = [rel_pos.x as f32, rel_pos.y as f32];
-                vertices[i].position_morph_target = vertices[morph_target_index].position;
-                vertices[i].local_texcoords = [
-                    x as f32 / (VERTICES_PER_PATCH - 1) as f32,
-                    y as f32 / (VERTICES_PER_PATCH - 1) as f32,
-                ];
-                vertices[i].color = [geometry.colors[i].x, geometry.colors[i].y, geometry.colors[i].z];
-            }
+        let mut heights: Vec<f32> = Vec::with_capacity(grid_vertex_count);
+        let mut vertices: Vec<Vertex> = Vec::with_capacity(geometry.positions.len());
+        let mut normals: Vec<(f32, f32, f32)> =
+            Vec::with_capacity(normals_per_patch * normals_per_patch);
+        let mut normals_low_detail: Vec<(f32, f32, f32)> =
+            Vec::with_capacity((normals_per_patch / 2) * (normals_per_patch / 2));
+
+        for pos in geometry.positions.iter() {
+            min = nalgebra::inf(&min, pos);
+            max = nalgebra::sup(&max, pos);
+        }
+
+        for (i, pos) in geometry.positions[..grid_vertex_count].iter().enumerate() {
+            let x = i % vertices_per_patch;
+            let y = (i - x) / vertices_per_patch;
+
+            let rel_pos = inverse_transform * (pos - origin);
+            heights.push(rel_pos.z as f32);
+
+            // Compute the vertex index that this vertex will morph to while morphing, vertices on
+            // even rows and columns morph to themselves
+            let morph_target_index = i - (x % 2) - ((y % 2) * vertices_per_patch);
+            let position = [rel_pos.x as f32, rel_pos.y as f32];
+            let position_morph_target = if morph_target_index == i {
+                position
+            } else {
+                vertices[morph_target_index].position
+            };
+
+            vertices.push(Vertex {
+                position,
+                position_morph_target,
+                local_texcoords: [
+                    x as f32 / (vertices_per_patch - 1) as f32,
+                    y as f32 / (vertices_per_patch - 1) as f32,
+                ],
+                color: [
+                    geometry.colors[i].x,
+                    geometry.colors[i].y,
+                    geometry.colors[i].z,
+                    geometry.colors[i].w,
+                ],
+                roughness: geometry.roughness[i],
+                skirt_depth: 0.0,
+            });
+        }
 
-            for (i, normal) in geometry.normals.iter().enumerate() {
-                let x = i % NORMALS_PER_PATCH;
-                let y = (i - x) / NORMALS_PER_PATCH;
+        // Skirt vertices are copies of the edge vertices that are moved down in the vertex shader,
+        // this way they follow the edge while morphing
+        let skirt_sources = planet::geometry_provider::skirt_source_indices(vertices_per_patch);
+        for (pos, source) in geometry.positions[grid_vertex_count..].iter().zip(skirt_sources) {
+            let rel_pos = inverse_transform * (pos - origin);
+            vertices.push(Vertex {
+                skirt_depth: heights[source] - rel_pos.z as f32,
+                ..vertices[source]
+            });
+        }
+
+        // The normals are stored in row-major order, so the low detail normals of every even row
+        // and column end up at the right index as well
+        for (i, normal) in geometry.normals.iter().enumerate() {
+            let x = i % normals_per_patch;
+            let y = (i - x) / normals_per_patch;
+
+            normals.push((normal.x as f32, normal.y as f32, normal.z as f32));
 
-                normals[i] = (normal.x as f32, normal.y as f32, normal.z as f32);
+            if x % 2 == 0 && y % 2 == 0 {
+                normals_low_detail.push((normal.x as f32, normal.y as f32, normal.z as f32));
+            }
+        }
 
-                if x % 2 == 0 && y % 2 == 0 {
-                    normals_low_detail[(y / 2) * (NORMALS_PER_PATCH / 2) + (x / 2)] =
-                        (normal.x as f32, normal.y as f32, normal.z as f32);
-                }
+        // The bounding box also contains the ocean surface above the patch
+        if let Some(sea_level) = description.sea_level {
+            let sea_radius = description.radius + sea_level;
+            for pos in geometry.positions.iter().filter(|pos| pos.coords.norm() < sea_radius) {
+                let surface = Point3::from_coordinates(pos.coords.normalize() * sea_radius);
+                min = nalgebra::inf(&min, &surface);
+                max = nalgebra::sup(&max, &surface);
             }
         }
 
@@ -1,4 +1,4 @@
-use super::Vertex;
+use super::{RendererConfig, Vertex};
 use crate::id_arena::{IdGenerator, SimpleIdArena};
 use glium::backend::Facade;
 use glium::buffer::BufferMutSlice;
@@ -117,6 +117,7 @@ where
 }
 
 pub struct NodeBacking {
+    pub config: RendererConfig,
     id_generator: SimpleIdArena,
     pub vertices: GeometryBuffer<Vertex>,
     pub heights: TextureAtlas<f32>,
@@ -124,28 +125,31 @@ pub struct NodeBacking {
 }
 
 impl NodeBacking {
-    pub fn new<F: ?Sized + Facade>(facade: &F) -> Result<NodeBacking, Box<std::error::Error>> {
-        use crate::planet::constants::{MAX_PATCH_COUNT, NORMALS_PER_PATCH, VERTICES_PER_PATCH};
+    pub fn new<F: ?Sized + Facade>(
+        facade: &F,
+        config: RendererConfig,
+    ) -> Result<NodeBacking, Box<std::error::Error>> {
         Ok(NodeBacking {
-            id_generator: SimpleIdArena::with_capacity(MAX_PATCH_COUNT),
+            config,
+            id_generator: SimpleIdArena::with_capacity(config.max_patch_count),
             vertices: GeometryBuffer::new(
                 facade,
-                MAX_PATCH_COUNT,
-                VERTICES_PER_PATCH * VERTICES_PER_PATCH,
+                config.max_patch_count,
+                config.patch_vertex_count(),
             )?,
             heights: TextureAtlas::new(
                 facade,
                 UncompressedFloatFormat::F32,
                 1,
-                MAX_PATCH_COUNT,
-                VERTICES_PER_PATCH,
+                config.max_patch_count,
+                config.vertices_per_patch,
             )?,
             normals: TextureAtlas::new(
                 facade,
                 UncompressedFloatFormat::F32F32F32,
                 2,
-                MAX_PATCH_COUNT,
-                NORMALS_PER_PATCH,
+                config.max_patch_count,
+                config.normals_per_patch(),
             )?,
         })
     }
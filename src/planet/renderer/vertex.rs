@@ -3,7 +3,20 @@ pub struct Vertex {
     pub position: [f32; 2],
     pub position_morph_target: [f32; 2],
     pub local_texcoords: [f32; 2],
-    pub color: [f32; 3],
+    pub color: [f32; 4],
+    pub roughness: f32,
+
+    /// Distance the vertex is moved down from the height in the height atlas, only skirt vertices
+    /// have a non-zero depth
+    pub skirt_depth: f32,
 }
 
-implement_vertex!(Vertex, position, position_morph_target, local_texcoords, color);
+implement_vertex!(
+    Vertex,
+    position,
+    position_morph_target,
+    local_texcoords,
+    color,
+    roughness,
+    skirt_depth
+);
@@ -0,0 +1,337 @@
+use nalgebra::Vector3;
+use simdnoise::{CellDistanceFunction, CellReturnType};
+
+/// Computes simplex noise for every point in `points` and writes the results to `out`. As many
+/// points as the CPU supports are computed at once, the results are identical to
+/// `simdnoise::scalar::simplex_3d`.
+pub fn simplex_3d(points: &[Vector3<f32>], out: &mut [f32]) {
+    let done = simd::simplex_3d(points, out);
+    for (p, out) in points[done..].iter().zip(out[done..].iter_mut()) {
+        *out = simdnoise::scalar::simplex_3d(p.x, p.y, p.z);
+    }
+}
+
+/// Computes cellular noise for every point in `points` and writes the results to `out`.
+pub fn cellular_3d(
+    points: &[Vector3<f32>],
+    distance_fn: CellDistanceFunction,
+    return_type: CellReturnType,
+    jitter: f32,
+    out: &mut [f32],
+) {
+    let done = simd::cellular_3d(points, distance_fn, return_type, jitter, out);
+    for (p, out) in points[done..].iter().zip(out[done..].iter_mut()) {
+        *out = simdnoise::scalar::cellular_3d(p.x, p.y, p.z, distance_fn, return_type, jitter);
+    }
+}
+
+/// Computes turbulence for every point in `points` and writes the results to `out`.
+pub fn turbulence_3d(
+    points: &[Vector3<f32>],
+    freq: f32,
+    lacunarity: f32,
+    gain: f32,
+    octaves: u8,
+    out: &mut [f32],
+) {
+    let done = simd::turbulence_3d(points, freq, lacunarity, gain, octaves, out);
+    for (p, out) in points[done..].iter().zip(out[done..].iter_mut()) {
+        *out = simdnoise::scalar::turbulence_3d(p.x, p.y, p.z, freq, lacunarity, gain, octaves);
+    }
+}
+
+/// The SIMD implementations. Every function returns the number of points it processed, the
+/// remaining points are left to the scalar implementation.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod simd {
+    use nalgebra::Vector3;
+    use simdnoise::{CellDistanceFunction, CellReturnType};
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    pub fn simplex_3d(points: &[Vector3<f32>], out: &mut [f32]) -> usize {
+        unsafe {
+            if is_x86_feature_detected!("avx2") {
+                simplex_3d_avx2(points, out)
+            } else if is_x86_feature_detected!("sse2") {
+                simplex_3d_sse2(points, out)
+            } else {
+                0
+            }
+        }
+    }
+
+    pub fn cellular_3d(
+        points: &[Vector3<f32>],
+        distance_fn: CellDistanceFunction,
+        return_type: CellReturnType,
+        jitter: f32,
+        out: &mut [f32],
+    ) -> usize {
+        unsafe {
+            if is_x86_feature_detected!("avx2") {
+                cellular_3d_avx2(points, distance_fn, return_type, jitter, out)
+            } else if is_x86_feature_detected!("sse2") {
+                cellular_3d_sse2(points, distance_fn, return_type, jitter, out)
+            } else {
+                0
+            }
+        }
+    }
+
+    pub fn turbulence_3d(
+        points: &[Vector3<f32>],
+        freq: f32,
+        lacunarity: f32,
+        gain: f32,
+        octaves: u8,
+        out: &mut [f32],
+    ) -> usize {
+        unsafe {
+            if is_x86_feature_detected!("avx2") {
+                turbulence_3d_avx2(points, freq, lacunarity, gain, octaves, out)
+            } else if is_x86_feature_detected!("sse2") {
+                turbulence_3d_sse2(points, freq, lacunarity, gain, octaves, out)
+            } else {
+                0
+            }
+        }
+    }
+
+    /// Transposes 8 points into one register per component
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load_avx2(points: &[Vector3<f32>]) -> (__m256, __m256, __m256) {
+        let mut x = [0.0; 8];
+        let mut y = [0.0; 8];
+        let mut z = [0.0; 8];
+        for (i, p) in points.iter().enumerate() {
+            x[i] = p.x;
+            y[i] = p.y;
+            z[i] = p.z;
+        }
+        (
+            _mm256_loadu_ps(x.as_ptr()),
+            _mm256_loadu_ps(y.as_ptr()),
+            _mm256_loadu_ps(z.as_ptr()),
+        )
+    }
+
+    /// Transposes 4 points into one register per component
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load_sse2(points: &[Vector3<f32>]) -> (__m128, __m128, __m128) {
+        let mut x = [0.0; 4];
+        let mut y = [0.0; 4];
+        let mut z = [0.0; 4];
+        for (i, p) in points.iter().enumerate() {
+            x[i] = p.x;
+            y[i] = p.y;
+            z[i] = p.z;
+        }
+        (
+            _mm_loadu_ps(x.as_ptr()),
+            _mm_loadu_ps(y.as_ptr()),
+            _mm_loadu_ps(z.as_ptr()),
+        )
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn simplex_3d_avx2(points: &[Vector3<f32>], out: &mut [f32]) -> usize {
+        for (points, out) in points.chunks_exact(8).zip(out.chunks_exact_mut(8)) {
+            let (x, y, z) = load_avx2(points);
+            _mm256_storeu_ps(out.as_mut_ptr(), simdnoise::avx2::simplex_3d(x, y, z));
+        }
+        points.len() / 8 * 8
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn simplex_3d_sse2(points: &[Vector3<f32>], out: &mut [f32]) -> usize {
+        for (points, out) in points.chunks_exact(4).zip(out.chunks_exact_mut(4)) {
+            let (x, y, z) = load_sse2(points);
+            _mm_storeu_ps(out.as_mut_ptr(), simdnoise::sse2::simplex_3d(x, y, z));
+        }
+        points.len() / 4 * 4
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn cellular_3d_avx2(
+        points: &[Vector3<f32>],
+        distance_fn: CellDistanceFunction,
+        return_type: CellReturnType,
+        jitter: f32,
+        out: &mut [f32],
+    ) -> usize {
+        let jitter = _mm256_set1_ps(jitter);
+        for (points, out) in points.chunks_exact(8).zip(out.chunks_exact_mut(8)) {
+            let (x, y, z) = load_avx2(points);
+            let noise = simdnoise::avx2::cellular_3d(x, y, z, distance_fn, return_type, jitter);
+            _mm256_storeu_ps(out.as_mut_ptr(), noise);
+        }
+        points.len() / 8 * 8
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn cellular_3d_sse2(
+        points: &[Vector3<f32>],
+        distance_fn: CellDistanceFunction,
+        return_type: CellReturnType,
+        jitter: f32,
+        out: &mut [f32],
+    ) -> usize {
+        let jitter = _mm_set1_ps(jitter);
+        for (points, out) in points.chunks_exact(4).zip(out.chunks_exact_mut(4)) {
+            let (x, y, z) = load_sse2(points);
+            let noise = simdnoise::sse2::cellular_3d(x, y, z, distance_fn, return_type, jitter);
+            _mm_storeu_ps(out.as_mut_ptr(), noise);
+        }
+        points.len() / 4 * 4
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn turbulence_3d_avx2(
+        points: &[Vector3<f32>],
+        freq: f32,
+        lacunarity: f32,
+        gain: f32,
+        octaves: u8,
+        out: &mut [f32],
+    ) -> usize {
+        let freq = _mm256_set1_ps(freq);
+        let lacunarity = _mm256_set1_ps(lacunarity);
+        let gain = _mm256_set1_ps(gain);
+        for (points, out) in points.chunks_exact(8).zip(out.chunks_exact_mut(8)) {
+            let (x, y, z) = load_avx2(points);
+            let noise = simdnoise::avx2::turbulence_3d(x, y, z, freq, lacunarity, gain, octaves);
+            _mm256_storeu_ps(out.as_mut_ptr(), noise);
+        }
+        points.len() / 8 * 8
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn turbulence_3d_sse2(
+        points: &[Vector3<f32>],
+        freq: f32,
+        lacunarity: f32,
+        gain: f32,
+        octaves: u8,
+        out: &mut [f32],
+    ) -> usize {
+        let freq = _mm_set1_ps(freq);
+        let lacunarity = _mm_set1_ps(lacunarity);
+        let gain = _mm_set1_ps(gain);
+        for (points, out) in points.chunks_exact(4).zip(out.chunks_exact_mut(4)) {
+            let (x, y, z) = load_sse2(points);
+            let noise = simdnoise::sse2::turbulence_3d(x, y, z, freq, lacunarity, gain, octaves);
+            _mm_storeu_ps(out.as_mut_ptr(), noise);
+        }
+        points.len() / 4 * 4
+    }
+}
+
+/// Fallback for platforms without SIMD implementations, all points are left to the scalar
+/// implementation.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+mod simd {
+    use nalgebra::Vector3;
+    use simdnoise::{CellDistanceFunction, CellReturnType};
+
+    pub fn simplex_3d(_: &[Vector3<f32>], _: &mut [f32]) -> usize {
+        0
+    }
+
+    pub fn cellular_3d(
+        _: &[Vector3<f32>],
+        _: CellDistanceFunction,
+        _: CellReturnType,
+        _: f32,
+        _: &mut [f32],
+    ) -> usize {
+        0
+    }
+
+    pub fn turbulence_3d(
+        _: &[Vector3<f32>],
+        _: f32,
+        _: f32,
+        _: f32,
+        _: u8,
+        _: &mut [f32],
+    ) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Not a multiple of the SIMD width, so the scalar remainder is covered as well
+    fn points() -> Vec<Vector3<f32>> {
+        (0..301)
+            .map(|i| {
+                let i = i as f32;
+                Vector3::new(
+                    (i * 0.731).sin() * 20.0,
+                    (i * 1.379).cos() * 20.0,
+                    (i * 0.291).sin() * (i * 0.117).cos() * 20.0,
+                )
+            })
+            .collect()
+    }
+
+    fn assert_close(values: &[f32], expected: impl Iterator<Item = f32>) {
+        for (i, (value, expected)) in values.iter().zip(expected).enumerate() {
+            assert!(
+                (value - expected).abs() <= 1.0e-5 * expected.abs().max(1.0),
+                "{} instead of {} at point {}",
+                value,
+                expected,
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn simplex_matches_scalar() {
+        let points = points();
+        let mut out = vec![0.0; points.len()];
+        simplex_3d(&points, &mut out);
+        assert_close(
+            &out,
+            points
+                .iter()
+                .map(|p| simdnoise::scalar::simplex_3d(p.x, p.y, p.z)),
+        );
+    }
+
+    #[test]
+    fn cellular_matches_scalar() {
+        let points = points();
+        let mut out = vec![0.0; points.len()];
+        let (distance_fn, return_type) =
+            (CellDistanceFunction::Euclidean, CellReturnType::Distance);
+        cellular_3d(&points, distance_fn, return_type, 0.45, &mut out);
+        assert_close(
+            &out,
+            points.iter().map(|p| {
+                simdnoise::scalar::cellular_3d(p.x, p.y, p.z, distance_fn, return_type, 0.45)
+            }),
+        );
+    }
+
+    #[test]
+    fn turbulence_matches_scalar() {
+        let points = points();
+        let mut out = vec![0.0; points.len()];
+        turbulence_3d(&points, 0.5, 2.0, 0.5, 4, &mut out);
+        assert_close(
+            &out,
+            points
+                .iter()
+                .map(|p| simdnoise::scalar::turbulence_3d(p.x, p.y, p.z, 0.5, 2.0, 0.5, 4)),
+        );
+    }
+}
@@ -1,7 +1,19 @@
-use nalgebra::Vector3;
+use crate::planet::biome::{self, Biome, Climate};
+use crate::planet::color_ramp::{ColorRamp, Material};
+use crate::planet::craters::Craters;
+use crate::planet::heightmap::{Filter, Heightmap};
+use crate::planet::noise;
+use crate::planet::simd_noise;
+use crate::planet::validation;
+use nalgebra::{Unit, UnitQuaternion, Vector3};
 use simdnoise::{CellDistanceFunction, CellReturnType};
+use std::collections::BTreeMap;
 use std::f32::{MAX, MIN};
 
+/// The smallest step used to compute gradients by central differences relative to the magnitude of
+/// the sample position, well above the precision of single precision samples
+const MIN_DIFFERENCE_STEP: f64 = 1.0e-5;
+
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "CellDistanceFunction")]
 enum CellDistanceFunctionDef {
@@ -24,26 +36,165 @@ pub enum TerrainLayer {
     Multiply(Vec<TerrainLayer>),
     Constant(f32),
     Clamp {min:Option<f32>, max:Option<f32>, value:Box<TerrainLayer>},
+    Min(Vec<TerrainLayer>),
+    Max(Vec<TerrainLayer>),
+    Abs(Box<TerrainLayer>),
+    Negate(Box<TerrainLayer>),
+
+    /// Raises the magnitude of `value` to `exponent`, the sign of `value` is preserved
+    Pow { exponent: f32, value: Box<TerrainLayer> },
+
+    /// Snaps `value` to the sorted terrace heights in `steps` with a quadratic falloff in between
+    Terrace {
+        steps: Vec<f32>,
+        #[serde(default)]
+        invert: bool,
+        value: Box<TerrainLayer>
+    },
+
+    /// Remaps `value` through a cubic spline running through the sorted (input, output) `points`
+    Curve { points: Vec<(f32, f32)>, value: Box<TerrainLayer> },
+
+    /// Selects `a` where `mask` is below `threshold` and `b` otherwise. Within `falloff` of the
+    /// threshold both layers are blended smoothly.
+    Select {
+        mask: Box<TerrainLayer>,
+        a: Box<TerrainLayer>,
+        b: Box<TerrainLayer>,
+        #[serde(default)]
+        threshold: f32,
+        #[serde(default)]
+        falloff: f32
+    },
+
+    /// Linearly blends between `a` and `b` using `mask`, a mask of 0 results in `a` and a mask of
+    /// 1 in `b`
+    Blend { mask: Box<TerrainLayer>, a: Box<TerrainLayer>, b: Box<TerrainLayer> },
+
+    /// Evaluates `value` at a position offset by the vector field (`x`, `y`, `z`) scaled by
+    /// `strength`
+    Warp {
+        strength: f32,
+        x: Box<TerrainLayer>,
+        y: Box<TerrainLayer>,
+        z: Box<TerrainLayer>,
+        value: Box<TerrainLayer>
+    },
+
+    /// Evaluates `value` with the sampling domain multiplied by `factor`
+    Scale { factor: f32, value: Box<TerrainLayer> },
+
+    /// Evaluates `value` with the sampling domain moved by `offset`
+    Translate { offset: [f32; 3], value: Box<TerrainLayer> },
+
+    /// Evaluates `value` with the sampling domain rotated around `axis` by `angle` degrees
+    Rotate { axis: [f32; 3], angle: f32, value: Box<TerrainLayer> },
+
+    /// Evaluates the layer with the given name from the `definitions` of the `Terrain`
+    Ref(String),
+
+    /// Scatters impact craters over the planet
+    Craters(Craters),
+
+    /// Samples heights from images on disk, the values in the images are multiplied by `scale`
+    Image {
+        source: Heightmap,
+        #[serde(default)]
+        filter: Filter,
+        scale: f32
+    },
+
     NoiseCellular {
         #[serde(with = "CellDistanceFunctionDef")]
         distance_fn: CellDistanceFunction,
         #[serde(with = "CellReturnTypeDef")]
         return_type: CellReturnType,
-        jitter: f32
+        jitter: f32,
+        #[serde(default)]
+        seed: u32,
+        #[serde(skip)]
+        salt: u32
     },
-    NoiseFBM { frequency: f32, persistence: f32, octaves: usize },
-    NoiseRidge { frequency: f32, persistence: f32, octaves: usize },
-    NoiseSimplex,
-    NoiseTurbulence { freq: f32, lacunarity: f32, gain: f32, octaves: u8 }
+    NoiseFBM { frequency: f32, persistence: f32, octaves: usize, #[serde(default)] seed: u32, #[serde(skip)] salt: u32 },
+    NoiseRidge { frequency: f32, persistence: f32, octaves: usize, #[serde(default)] seed: u32, #[serde(skip)] salt: u32 },
+    NoiseSimplex { #[serde(default)] seed: u32, #[serde(skip)] salt: u32 },
+    NoiseTurbulence { freq: f32, lacunarity: f32, gain: f32, octaves: u8, #[serde(default)] seed: u32, #[serde(skip)] salt: u32 }
 }
 
 impl TerrainLayer {
-    pub fn compute_height(&self, dir: &Vector3<f32>) -> f32 {
+    /// Returns the layers that are directly used by this layer
+    pub fn children(&self) -> Vec<&TerrainLayer> {
+        match self {
+            TerrainLayer::Add(children)
+            | TerrainLayer::Multiply(children)
+            | TerrainLayer::Min(children)
+            | TerrainLayer::Max(children) => children.iter().collect(),
+            TerrainLayer::Clamp { value, .. }
+            | TerrainLayer::Abs(value)
+            | TerrainLayer::Negate(value)
+            | TerrainLayer::Pow { value, .. }
+            | TerrainLayer::Terrace { value, .. }
+            | TerrainLayer::Curve { value, .. }
+            | TerrainLayer::Scale { value, .. }
+            | TerrainLayer::Translate { value, .. }
+            | TerrainLayer::Rotate { value, .. } => vec![&**value],
+            TerrainLayer::Select { mask, a, b, .. } | TerrainLayer::Blend { mask, a, b } => {
+                vec![&**mask, &**a, &**b]
+            }
+            TerrainLayer::Warp { x, y, z, value, .. } => vec![&**x, &**y, &**z, &**value],
+            TerrainLayer::Ref(_)
+            | TerrainLayer::Image { .. }
+            | TerrainLayer::Craters(_)
+            | TerrainLayer::Constant(_)
+            | TerrainLayer::NoiseCellular { .. }
+            | TerrainLayer::NoiseFBM { .. }
+            | TerrainLayer::NoiseRidge { .. }
+            | TerrainLayer::NoiseSimplex { .. }
+            | TerrainLayer::NoiseTurbulence { .. } => Vec::new(),
+        }
+    }
+
+    /// Returns the layers that are directly used by this layer for modification
+    fn children_mut(&mut self) -> Vec<&mut TerrainLayer> {
+        match self {
+            TerrainLayer::Add(children)
+            | TerrainLayer::Multiply(children)
+            | TerrainLayer::Min(children)
+            | TerrainLayer::Max(children) => children.iter_mut().collect(),
+            TerrainLayer::Clamp { value, .. }
+            | TerrainLayer::Abs(value)
+            | TerrainLayer::Negate(value)
+            | TerrainLayer::Pow { value, .. }
+            | TerrainLayer::Terrace { value, .. }
+            | TerrainLayer::Curve { value, .. }
+            | TerrainLayer::Scale { value, .. }
+            | TerrainLayer::Translate { value, .. }
+            | TerrainLayer::Rotate { value, .. } => vec![&mut **value],
+            TerrainLayer::Select { mask, a, b, .. } | TerrainLayer::Blend { mask, a, b } => {
+                vec![&mut **mask, &mut **a, &mut **b]
+            }
+            TerrainLayer::Warp { x, y, z, value, .. } => {
+                vec![&mut **x, &mut **y, &mut **z, &mut **value]
+            }
+            TerrainLayer::Ref(_)
+            | TerrainLayer::Image { .. }
+            | TerrainLayer::Craters(_)
+            | TerrainLayer::Constant(_)
+            | TerrainLayer::NoiseCellular { .. }
+            | TerrainLayer::NoiseFBM { .. }
+            | TerrainLayer::NoiseRidge { .. }
+            | TerrainLayer::NoiseSimplex { .. }
+            | TerrainLayer::NoiseTurbulence { .. } => Vec::new(),
+        }
+    }
+
+    /// Computes the height of the terrain in the direction `dir`.
+    pub fn compute_height(&self, dir: &Vector3<f32>, context: &mut TerrainContext) -> f32 {
         match self {
             TerrainLayer::Add (children) => {
                 let mut height : f32 = 0.0;
                 for child in children {
-                    let child_height = child.compute_height(dir);
+                    let child_height = child.compute_height(dir, context);
                     height += child_height;
                 }
                 height
@@ -51,27 +202,88 @@ impl TerrainLayer {
             TerrainLayer::Multiply (children) => {
                 let mut height : f32 = 1.0;
                 for child in children {
-                    let child_height = child.compute_height(dir);
+                    let child_height = child.compute_height(dir, context);
                     height *= child_height;
                 }
                 height
             },
             TerrainLayer::Clamp {min, max, value } => {
-                value.compute_height(dir)
+                value.compute_height(dir, context)
                     .min(max.unwrap_or(MAX ))
                     .max( min.unwrap_or( MIN ))
             }
+            TerrainLayer::Min(children) => {
+                children.iter().fold(MAX, |height, child| height.min(child.compute_height(dir, context)))
+            },
+            TerrainLayer::Max(children) => {
+                children.iter().fold(MIN, |height, child| height.max(child.compute_height(dir, context)))
+            },
+            TerrainLayer::Abs(value) => value.compute_height(dir, context).abs(),
+            TerrainLayer::Negate(value) => -value.compute_height(dir, context),
+            TerrainLayer::Pow { exponent, value } => {
+                let height = value.compute_height(dir, context);
+                height.signum() * height.abs().powf(*exponent)
+            },
+            TerrainLayer::Terrace { steps, invert, value } => {
+                terrace(value.compute_height(dir, context), steps, *invert).0
+            },
+            TerrainLayer::Curve { points, value } => {
+                curve(value.compute_height(dir, context), points).0
+            },
+            TerrainLayer::Select { mask, a, b, threshold, falloff } => {
+                let mask = mask.compute_height(dir, context);
+                if mask < threshold - falloff {
+                    a.compute_height(dir, context)
+                } else if mask >= threshold + falloff {
+                    b.compute_height(dir, context)
+                } else {
+                    let t = smoothstep((mask - (threshold - falloff)) / (2.0 * falloff));
+                    lerp(a.compute_height(dir, context), b.compute_height(dir, context), t)
+                }
+            },
+            TerrainLayer::Blend { mask, a, b } => {
+                let t = mask.compute_height(dir, context).max(0.0).min(1.0);
+                lerp(a.compute_height(dir, context), b.compute_height(dir, context), t)
+            },
+            TerrainLayer::Warp { strength, x, y, z, value } => {
+                let offset = Vector3::new(
+                    x.compute_height(dir, context),
+                    y.compute_height(dir, context),
+                    z.compute_height(dir, context),
+                );
+                value.compute_height(&(dir + offset * *strength), context)
+            },
+            TerrainLayer::Scale { factor, value } => {
+                context.with_scaled_footprint(*factor, |context| value.compute_height(&(dir * *factor), context))
+            },
+            TerrainLayer::Translate { offset, value } => {
+                value.compute_height(&(dir + Vector3::new(offset[0], offset[1], offset[2])), context)
+            },
+            TerrainLayer::Rotate { axis, angle, value } => {
+                let axis = Unit::new_normalize(Vector3::new(axis[0], axis[1], axis[2]));
+                let rotation = UnitQuaternion::from_axis_angle(&axis, angle.to_radians());
+                value.compute_height(&(rotation * dir), context)
+            },
+            TerrainLayer::Ref(name) => context.compute_definition(name, dir),
+            TerrainLayer::Craters(craters) => craters.compute_height(dir, context.planet_seed),
+            TerrainLayer::Image { source, filter, scale } => source.sample(dir, *filter) * scale,
             TerrainLayer::Constant(height) => *height,
-            TerrainLayer::NoiseCellular { distance_fn, return_type, jitter } => {
-                simdnoise::scalar::cellular_3d(dir.x, dir.y, dir.z, *distance_fn, *return_type, *jitter)
+            TerrainLayer::NoiseCellular { distance_fn, return_type, jitter, seed, salt } => {
+                let p = dir + domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0);
+                simdnoise::scalar::cellular_3d(p.x, p.y, p.z, *distance_fn, *return_type, *jitter)
             },
-            TerrainLayer::NoiseFBM { frequency: freq, persistence, octaves } => {
+            TerrainLayer::NoiseFBM { frequency: freq, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
                 let mut result = 0.0;
                 let mut max_amplitude = 0.0;
                 let mut amplitide = 1.0;
                 let mut frequency = *freq;
-                for _ in 0..*octaves {
-                    result += ((1.0 - simdnoise::scalar::simplex_3d(dir.x*frequency, dir.y*frequency, dir.z*frequency).abs()) * 2.0 - 1.0) * amplitide;
+                for octave in 0..*octaves {
+                    let weight = octave_weight(frequency, context.footprint);
+                    if weight > 0.0 {
+                        let p = dir * frequency + domain_offset(seed, octave as u32);
+                        result += ((1.0 - simdnoise::scalar::simplex_3d(p.x, p.y, p.z).abs()) * 2.0 - 1.0) * amplitide * weight;
+                    }
                     frequency *= 2.0;
                     max_amplitude += amplitide;
                     amplitide *= persistence;
@@ -79,13 +291,18 @@ impl TerrainLayer {
 
                 result/max_amplitude
             },
-            TerrainLayer::NoiseRidge { frequency: freq, persistence, octaves } => {
+            TerrainLayer::NoiseRidge { frequency: freq, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
                 let mut result = 0.0;
                 let mut max_amplitude = 0.0;
                 let mut amplitide = 1.0;
                 let mut frequency = *freq;
-                for _ in 0..*octaves {
-                    result += simdnoise::scalar::simplex_3d(dir.x*frequency, dir.y*frequency, dir.z*frequency) * amplitide;
+                for octave in 0..*octaves {
+                    let weight = octave_weight(frequency, context.footprint);
+                    if weight > 0.0 {
+                        let p = dir * frequency + domain_offset(seed, octave as u32);
+                        result += simdnoise::scalar::simplex_3d(p.x, p.y, p.z) * amplitide * weight;
+                    }
                     frequency *= 2.0;
                     max_amplitude += amplitide;
                     amplitide *= persistence;
@@ -93,50 +310,1384 @@ impl TerrainLayer {
 
                 result/max_amplitude
             },
-            TerrainLayer::NoiseSimplex => {
-                simdnoise::scalar::simplex_3d(dir.x, dir.y, dir.z)
+            TerrainLayer::NoiseSimplex { seed, salt } => {
+                let p = dir + domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0);
+                simdnoise::scalar::simplex_3d(p.x, p.y, p.z)
             },
-            TerrainLayer::NoiseTurbulence { freq, lacunarity, gain, octaves } => {
-                simdnoise::scalar::turbulence_3d(dir.x, dir.y, dir.z, *freq, *lacunarity, *gain, *octaves)
+            TerrainLayer::NoiseTurbulence { freq, lacunarity, gain, octaves, seed, salt } => {
+                let p = dir + domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0) / *freq;
+                let octaves = turbulence_octaves(*freq, *lacunarity, *octaves, context.footprint);
+                simdnoise::scalar::turbulence_3d(p.x, p.y, p.z, *freq, *lacunarity, *gain, octaves)
             }
         }
     }
 
-    pub fn compute_height_and_color(&self, dir: &Vector3<f32>) -> (f32, Vector3<f32>) {
-        let height = self.compute_height(&dir);
-        let color = self.compute_color_from_height(height);
-        (height, color)
+    /// Computes the height of the terrain in the direction `dir` like `compute_height` but with
+    /// the sampling domain and the simplex based noise in double precision. This prevents the
+    /// terrain from stair-stepping when the distance between samples approaches the resolution of
+    /// single precision floats. Cellular noise, turbulence, craters and images are still sampled
+    /// in single precision.
+    pub fn compute_height_f64(&self, dir: &Vector3<f64>, context: &mut TerrainContext) -> f32 {
+        match self {
+            TerrainLayer::Add(children) => {
+                children.iter().fold(0.0, |height, child| height + child.compute_height_f64(dir, context))
+            },
+            TerrainLayer::Multiply(children) => {
+                children.iter().fold(1.0, |height, child| height * child.compute_height_f64(dir, context))
+            },
+            TerrainLayer::Clamp { min, max, value } => {
+                value.compute_height_f64(dir, context)
+                    .min(max.unwrap_or(MAX))
+                    .max(min.unwrap_or(MIN))
+            },
+            TerrainLayer::Min(children) => {
+                children.iter().fold(MAX, |height, child| height.min(child.compute_height_f64(dir, context)))
+            },
+            TerrainLayer::Max(children) => {
+                children.iter().fold(MIN, |height, child| height.max(child.compute_height_f64(dir, context)))
+            },
+            TerrainLayer::Abs(value) => value.compute_height_f64(dir, context).abs(),
+            TerrainLayer::Negate(value) => -value.compute_height_f64(dir, context),
+            TerrainLayer::Pow { exponent, value } => {
+                let height = value.compute_height_f64(dir, context);
+                height.signum() * height.abs().powf(*exponent)
+            },
+            TerrainLayer::Terrace { steps, invert, value } => {
+                terrace(value.compute_height_f64(dir, context), steps, *invert).0
+            },
+            TerrainLayer::Curve { points, value } => {
+                curve(value.compute_height_f64(dir, context), points).0
+            },
+            TerrainLayer::Select { mask, a, b, threshold, falloff } => {
+                let mask = mask.compute_height_f64(dir, context);
+                if mask < threshold - falloff {
+                    a.compute_height_f64(dir, context)
+                } else if mask >= threshold + falloff {
+                    b.compute_height_f64(dir, context)
+                } else {
+                    let t = smoothstep((mask - (threshold - falloff)) / (2.0 * falloff));
+                    lerp(a.compute_height_f64(dir, context), b.compute_height_f64(dir, context), t)
+                }
+            },
+            TerrainLayer::Blend { mask, a, b } => {
+                let t = mask.compute_height_f64(dir, context).max(0.0).min(1.0);
+                lerp(a.compute_height_f64(dir, context), b.compute_height_f64(dir, context), t)
+            },
+            TerrainLayer::Warp { strength, x, y, z, value } => {
+                let offset = Vector3::new(
+                    x.compute_height_f64(dir, context),
+                    y.compute_height_f64(dir, context),
+                    z.compute_height_f64(dir, context),
+                );
+                value.compute_height_f64(&(dir + to_f64(&offset) * f64::from(*strength)), context)
+            },
+            TerrainLayer::Scale { factor, value } => {
+                context.with_scaled_footprint(*factor, |context| {
+                    value.compute_height_f64(&(dir * f64::from(*factor)), context)
+                })
+            },
+            TerrainLayer::Translate { offset, value } => {
+                value.compute_height_f64(&(dir + to_f64(&Vector3::new(offset[0], offset[1], offset[2]))), context)
+            },
+            TerrainLayer::Rotate { axis, angle, value } => {
+                value.compute_height_f64(&(rotation_f64(axis, *angle) * dir), context)
+            },
+            TerrainLayer::Ref(name) => context.compute_definition_f64(name, dir),
+            TerrainLayer::Constant(height) => *height,
+            TerrainLayer::NoiseFBM { frequency: freq, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
+                fractal_height(dir, *freq, *persistence, *octaves, seed, context.footprint, fbm_shape)
+            },
+            TerrainLayer::NoiseRidge { frequency: freq, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
+                fractal_height(dir, *freq, *persistence, *octaves, seed, context.footprint, ridge_shape)
+            },
+            TerrainLayer::NoiseSimplex { seed, salt } => {
+                let p = dir + to_f64(&domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0));
+                noise::simplex_value_3d(&p) as f32
+            },
+
+            // There is no double precision implementation of these layers yet, they are sampled in
+            // the direction rounded to single precision and stair-step like `Precision::Single`
+            TerrainLayer::Craters(_)
+            | TerrainLayer::Image { .. }
+            | TerrainLayer::NoiseCellular { .. }
+            | TerrainLayer::NoiseTurbulence { .. } => self.compute_height(&to_f32(dir), context),
+        }
     }
 
-    fn compute_color_from_height(&self, height: f32) -> Vector3<f32> {
-        // TODO: get this mapping from the terrain parameter file
-        let mapping = [
-            (0.0, Vector3::new(0.0, 0.0, 0.6)),
-            (50.0, Vector3::new(0.0, 0.5, 0.0)),
-            (700.0, Vector3::new(0.0, 0.5, 0.0)),
-            (1000.0, Vector3::new(0.5, 0.5, 0.5)),
-        ];
+    /// Computes the height of the terrain in the direction `dir` together with the gradient of the
+    /// height with respect to `dir`. The gradient is derived analytically by applying the chain
+    /// rule through the layers, only craters, images, cellular noise and turbulence fall back to
+    /// central differences across the footprint of the context. Like `compute_height_f64` the
+    /// sampling domain is kept in double precision.
+    pub fn compute_height_and_gradient(&self, dir: &Vector3<f64>, context: &mut TerrainContext) -> (f32, Vector3<f32>) {
+        match self {
+            TerrainLayer::Add(children) => {
+                let mut height = 0.0;
+                let mut gradient = Vector3::zeros();
+                for child in children {
+                    let (child_height, child_gradient) = child.compute_height_and_gradient(dir, context);
+                    height += child_height;
+                    gradient += child_gradient;
+                }
+                (height, gradient)
+            },
+            TerrainLayer::Multiply(children) => {
+                let mut height = 1.0;
+                let mut gradient = Vector3::zeros();
+                for child in children {
+                    let (child_height, child_gradient) = child.compute_height_and_gradient(dir, context);
+                    gradient = gradient * child_height + child_gradient * height;
+                    height *= child_height;
+                }
+                (height, gradient)
+            },
+            TerrainLayer::Clamp { min, max, value } => {
+                let (height, gradient) = value.compute_height_and_gradient(dir, context);
+                if height > max.unwrap_or(MAX) {
+                    (max.unwrap_or(MAX).max(min.unwrap_or(MIN)), Vector3::zeros())
+                } else if height < min.unwrap_or(MIN) {
+                    (min.unwrap_or(MIN), Vector3::zeros())
+                } else {
+                    (height, gradient)
+                }
+            },
+            TerrainLayer::Min(children) => {
+                children.iter().fold((MAX, Vector3::zeros()), |result, child| {
+                    let child = child.compute_height_and_gradient(dir, context);
+                    if child.0 < result.0 { child } else { result }
+                })
+            },
+            TerrainLayer::Max(children) => {
+                children.iter().fold((MIN, Vector3::zeros()), |result, child| {
+                    let child = child.compute_height_and_gradient(dir, context);
+                    if child.0 > result.0 { child } else { result }
+                })
+            },
+            TerrainLayer::Abs(value) => {
+                let (height, gradient) = value.compute_height_and_gradient(dir, context);
+                (height.abs(), gradient * height.signum())
+            },
+            TerrainLayer::Negate(value) => {
+                let (height, gradient) = value.compute_height_and_gradient(dir, context);
+                (-height, -gradient)
+            },
+            TerrainLayer::Pow { exponent, value } => {
+                let (height, gradient) = value.compute_height_and_gradient(dir, context);
+                let slope = if height == 0.0 { 0.0 } else { exponent * height.abs().powf(exponent - 1.0) };
+                (height.signum() * height.abs().powf(*exponent), gradient * slope)
+            },
+            TerrainLayer::Terrace { steps, invert, value } => {
+                let (height, gradient) = value.compute_height_and_gradient(dir, context);
+                let (height, slope) = terrace(height, steps, *invert);
+                (height, gradient * slope)
+            },
+            TerrainLayer::Curve { points, value } => {
+                let (height, gradient) = value.compute_height_and_gradient(dir, context);
+                let (height, slope) = curve(height, points);
+                (height, gradient * slope)
+            },
+            TerrainLayer::Select { mask, a, b, threshold, falloff } => {
+                let (mask, mask_gradient) = mask.compute_height_and_gradient(dir, context);
+                if mask < threshold - falloff {
+                    a.compute_height_and_gradient(dir, context)
+                } else if mask >= threshold + falloff {
+                    b.compute_height_and_gradient(dir, context)
+                } else {
+                    let x = (mask - (threshold - falloff)) / (2.0 * falloff);
+                    let t = smoothstep(x);
+                    let t_gradient = mask_gradient * (6.0 * x * (1.0 - x) / (2.0 * falloff));
+                    blend_with_gradient(
+                        a.compute_height_and_gradient(dir, context),
+                        b.compute_height_and_gradient(dir, context),
+                        (t, t_gradient),
+                    )
+                }
+            },
+            TerrainLayer::Blend { mask, a, b } => {
+                let (mask, mask_gradient) = mask.compute_height_and_gradient(dir, context);
+                let t = if mask > 0.0 && mask < 1.0 {
+                    (mask, mask_gradient)
+                } else {
+                    (mask.max(0.0).min(1.0), Vector3::zeros())
+                };
+                blend_with_gradient(
+                    a.compute_height_and_gradient(dir, context),
+                    b.compute_height_and_gradient(dir, context),
+                    t,
+                )
+            },
+            TerrainLayer::Warp { strength, x, y, z, value } => {
+                let (x, x_gradient) = x.compute_height_and_gradient(dir, context);
+                let (y, y_gradient) = y.compute_height_and_gradient(dir, context);
+                let (z, z_gradient) = z.compute_height_and_gradient(dir, context);
+                let warped = dir + to_f64(&Vector3::new(x, y, z)) * f64::from(*strength);
+                let (height, gradient) = value.compute_height_and_gradient(&warped, context);
+
+                // Multiply by the transposed Jacobian of the warp: I + strength * d(x, y, z)/d(dir)
+                let warp_gradient = x_gradient * gradient.x + y_gradient * gradient.y + z_gradient * gradient.z;
+                (height, gradient + warp_gradient * *strength)
+            },
+            TerrainLayer::Scale { factor, value } => {
+                let (height, gradient) = context.with_scaled_footprint(*factor, |context| {
+                    value.compute_height_and_gradient(&(dir * f64::from(*factor)), context)
+                });
+                (height, gradient * *factor)
+            },
+            TerrainLayer::Translate { offset, value } => {
+                value.compute_height_and_gradient(&(dir + to_f64(&Vector3::new(offset[0], offset[1], offset[2]))), context)
+            },
+            TerrainLayer::Rotate { axis, angle, value } => {
+                let rotation = rotation_f64(axis, *angle);
+                let (height, gradient) = value.compute_height_and_gradient(&(rotation * dir), context);
+                (height, to_f32(&(rotation.inverse() * to_f64(&gradient))))
+            },
+            TerrainLayer::Ref(name) => context.compute_definition_and_gradient(name, dir),
+            TerrainLayer::Constant(height) => (*height, Vector3::zeros()),
+            TerrainLayer::NoiseFBM { frequency: freq, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
+                fractal_height_and_gradient(dir, *freq, *persistence, *octaves, seed, context.footprint, fbm_shape)
+            },
+            TerrainLayer::NoiseRidge { frequency: freq, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
+                fractal_height_and_gradient(dir, *freq, *persistence, *octaves, seed, context.footprint, ridge_shape)
+            },
+            TerrainLayer::NoiseSimplex { seed, salt } => {
+                let p = dir + to_f64(&domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0));
+                let (height, gradient) = noise::simplex_3d(&p);
+                (height as f32, to_f32(&gradient))
+            },
+
+            // Layers without an analytic derivative use central differences across one footprint,
+            // the smallest detail the samples can show. The step never drops below what these
+            // layers, which sample in single precision, can resolve at `dir`.
+            TerrainLayer::Craters(_)
+            | TerrainLayer::Image { .. }
+            | TerrainLayer::NoiseCellular { .. }
+            | TerrainLayer::NoiseTurbulence { .. } => {
+                let eps = (f64::from(context.footprint) * 0.5).max(MIN_DIFFERENCE_STEP * dir.norm());
+                let mut difference = |axis: Vector3<f64>| {
+                    let high = self.compute_height_f64(&(dir + axis * eps), context);
+                    let low = self.compute_height_f64(&(dir - axis * eps), context);
+                    ((f64::from(high) - f64::from(low)) / (2.0 * eps)) as f32
+                };
+                let gradient = Vector3::new(
+                    difference(Vector3::new(1.0, 0.0, 0.0)),
+                    difference(Vector3::new(0.0, 1.0, 0.0)),
+                    difference(Vector3::new(0.0, 0.0, 1.0)),
+                );
+                (self.compute_height_f64(dir, context), gradient)
+            }
+        }
+    }
+
+    /// Computes the height of the terrain for every direction in `dirs` at once. The results are
+    /// identical to calling `compute_height` for every direction, but noise is evaluated for
+    /// multiple directions at the same time using SIMD instructions.
+    pub fn compute_heights(&self, dirs: &[Vector3<f32>], context: &mut TerrainContext) -> Vec<f32> {
+        match self {
+            TerrainLayer::Add(children) => {
+                let mut heights = vec![0.0; dirs.len()];
+                for child in children {
+                    let child_heights = child.compute_heights(dirs, context);
+                    zip_with(&mut heights, &child_heights, |height, child| height + child);
+                }
+                heights
+            },
+            TerrainLayer::Multiply(children) => {
+                let mut heights = vec![1.0; dirs.len()];
+                for child in children {
+                    let child_heights = child.compute_heights(dirs, context);
+                    zip_with(&mut heights, &child_heights, |height, child| height * child);
+                }
+                heights
+            },
+            TerrainLayer::Clamp { min, max, value } => {
+                let (min, max) = (min.unwrap_or(MIN), max.unwrap_or(MAX));
+                map(value.compute_heights(dirs, context), |height| height.min(max).max(min))
+            },
+            TerrainLayer::Min(children) => {
+                let mut heights = vec![MAX; dirs.len()];
+                for child in children {
+                    let child_heights = child.compute_heights(dirs, context);
+                    zip_with(&mut heights, &child_heights, f32::min);
+                }
+                heights
+            },
+            TerrainLayer::Max(children) => {
+                let mut heights = vec![MIN; dirs.len()];
+                for child in children {
+                    let child_heights = child.compute_heights(dirs, context);
+                    zip_with(&mut heights, &child_heights, f32::max);
+                }
+                heights
+            },
+            TerrainLayer::Abs(value) => map(value.compute_heights(dirs, context), f32::abs),
+            TerrainLayer::Negate(value) => map(value.compute_heights(dirs, context), |height| -height),
+            TerrainLayer::Pow { exponent, value } => {
+                map(value.compute_heights(dirs, context), |height| {
+                    height.signum() * height.abs().powf(*exponent)
+                })
+            },
+            TerrainLayer::Terrace { steps, invert, value } => {
+                map(value.compute_heights(dirs, context), |height| terrace(height, steps, *invert).0)
+            },
+            TerrainLayer::Curve { points, value } => {
+                map(value.compute_heights(dirs, context), |height| curve(height, points).0)
+            },
+            TerrainLayer::Select { mask, a, b, threshold, falloff } => {
+                let (low, high) = (threshold - falloff, threshold + falloff);
+                let mut heights = mask.compute_heights(dirs, context);
+
+                // Layers that are not selected anywhere in the batch are not evaluated at all
+                let a = if heights.iter().any(|&mask| mask < high) {
+                    a.compute_heights(dirs, context)
+                } else {
+                    Vec::new()
+                };
+                let b = if heights.iter().any(|&mask| mask >= low) {
+                    b.compute_heights(dirs, context)
+                } else {
+                    Vec::new()
+                };
+
+                for (i, height) in heights.iter_mut().enumerate() {
+                    let mask = *height;
+                    *height = if mask < low {
+                        a[i]
+                    } else if mask >= high {
+                        b[i]
+                    } else {
+                        lerp(a[i], b[i], smoothstep((mask - low) / (2.0 * falloff)))
+                    };
+                }
+                heights
+            },
+            TerrainLayer::Blend { mask, a, b } => {
+                let mut heights = mask.compute_heights(dirs, context);
+                let a = a.compute_heights(dirs, context);
+                let b = b.compute_heights(dirs, context);
+                for (i, height) in heights.iter_mut().enumerate() {
+                    *height = lerp(a[i], b[i], height.max(0.0).min(1.0));
+                }
+                heights
+            },
+            TerrainLayer::Warp { strength, x, y, z, value } => {
+                let x = x.compute_heights(dirs, context);
+                let y = y.compute_heights(dirs, context);
+                let z = z.compute_heights(dirs, context);
+                let warped: Vec<Vector3<f32>> = dirs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, dir)| dir + Vector3::new(x[i], y[i], z[i]) * *strength)
+                    .collect();
+                value.compute_heights(&warped, context)
+            },
+            TerrainLayer::Scale { factor, value } => {
+                let scaled: Vec<Vector3<f32>> = dirs.iter().map(|dir| dir * *factor).collect();
+                context.with_scaled_footprint(*factor, |context| value.compute_heights(&scaled, context))
+            },
+            TerrainLayer::Translate { offset, value } => {
+                let offset = Vector3::new(offset[0], offset[1], offset[2]);
+                let translated: Vec<Vector3<f32>> = dirs.iter().map(|dir| dir + offset).collect();
+                value.compute_heights(&translated, context)
+            },
+            TerrainLayer::Rotate { axis, angle, value } => {
+                let axis = Unit::new_normalize(Vector3::new(axis[0], axis[1], axis[2]));
+                let rotation = UnitQuaternion::from_axis_angle(&axis, angle.to_radians());
+                let rotated: Vec<Vector3<f32>> = dirs.iter().map(|dir| rotation * dir).collect();
+                value.compute_heights(&rotated, context)
+            },
+            TerrainLayer::Ref(name) => context.compute_definitions(name, dirs),
+            TerrainLayer::Constant(height) => vec![*height; dirs.len()],
+            TerrainLayer::NoiseCellular { distance_fn, return_type, jitter, seed, salt } => {
+                let offset = domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0);
+                let points: Vec<Vector3<f32>> = dirs.iter().map(|dir| dir + offset).collect();
+                let mut heights = vec![0.0; dirs.len()];
+                simd_noise::cellular_3d(&points, *distance_fn, *return_type, *jitter, &mut heights);
+                heights
+            },
+            TerrainLayer::NoiseFBM { frequency, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
+                let footprint = context.footprint;
+                fractal_heights(dirs, *frequency, *persistence, *octaves, seed, footprint, |noise| fbm_shape(noise).0)
+            },
+            TerrainLayer::NoiseRidge { frequency, persistence, octaves, seed, salt } => {
+                let seed = combine_seeds(context.planet_seed, *seed ^ *salt);
+                let footprint = context.footprint;
+                fractal_heights(dirs, *frequency, *persistence, *octaves, seed, footprint, |noise| noise)
+            },
+            TerrainLayer::NoiseSimplex { seed, salt } => {
+                let offset = domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0);
+                let points: Vec<Vector3<f32>> = dirs.iter().map(|dir| dir + offset).collect();
+                let mut heights = vec![0.0; dirs.len()];
+                simd_noise::simplex_3d(&points, &mut heights);
+                heights
+            },
+            TerrainLayer::NoiseTurbulence { freq, lacunarity, gain, octaves, seed, salt } => {
+                let offset = domain_offset(combine_seeds(context.planet_seed, *seed ^ *salt), 0) / *freq;
+                let points: Vec<Vector3<f32>> = dirs.iter().map(|dir| dir + offset).collect();
+                let mut heights = vec![0.0; dirs.len()];
+                let octaves = turbulence_octaves(*freq, *lacunarity, *octaves, context.footprint);
+                simd_noise::turbulence_3d(&points, *freq, *lacunarity, *gain, octaves, &mut heights);
+                heights
+            },
+
+            // Layers without a batched implementation are evaluated one direction at a time
+            TerrainLayer::Craters(_) | TerrainLayer::Image { .. } => {
+                dirs.iter().map(|dir| self.compute_height(dir, context)).collect()
+            }
+        }
+    }
+}
+
+/// Sums `octaves` octaves of simplex noise for every direction in `dirs`. Every octave doubles the
+/// frequency and multiplies the amplitude by `persistence`, `shape` is applied to the noise of
+/// every octave before it is added. Octaves too fine for the `footprint` are faded out.
+fn fractal_heights(
+    dirs: &[Vector3<f32>],
+    frequency: f32,
+    persistence: f32,
+    octaves: usize,
+    seed: u32,
+    footprint: f32,
+    shape: impl Fn(f32) -> f32,
+) -> Vec<f32> {
+    let mut heights = vec![0.0; dirs.len()];
+    let mut noise = vec![0.0; dirs.len()];
+    let mut points = Vec::with_capacity(dirs.len());
+    let mut max_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = frequency;
+    for octave in 0..octaves {
+        let weight = octave_weight(frequency, footprint) * amplitude;
+        if weight > 0.0 {
+            let offset = domain_offset(seed, octave as u32);
+            points.clear();
+            points.extend(dirs.iter().map(|dir| dir * frequency + offset));
+            simd_noise::simplex_3d(&points, &mut noise);
+            zip_with(&mut heights, &noise, |height, noise| height + shape(noise) * weight);
+        }
+        frequency *= 2.0;
+        max_amplitude += amplitude;
+        amplitude *= persistence;
+    }
+
+    map(heights, |height| height / max_amplitude)
+}
+
+/// Computes the height of fractal simplex noise in double precision in the direction `dir`, see
+/// `fractal_heights`. `shape` returns the shaped noise and its derivative, only the former is used.
+fn fractal_height(
+    dir: &Vector3<f64>,
+    frequency: f32,
+    persistence: f32,
+    octaves: usize,
+    seed: u32,
+    footprint: f32,
+    shape: impl Fn(f32) -> (f32, f32),
+) -> f32 {
+    let mut height = 0.0;
+    let mut max_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = f64::from(frequency);
+    for octave in 0..octaves {
+        let weight = octave_weight(frequency as f32, footprint) * amplitude;
+        if weight > 0.0 {
+            let p = dir * frequency + to_f64(&domain_offset(seed, octave as u32));
+            height += shape(noise::simplex_value_3d(&p) as f32).0 * weight;
+        }
+        frequency *= 2.0;
+        max_amplitude += amplitude;
+        amplitude *= persistence;
+    }
+
+    height / max_amplitude
+}
+
+/// Computes the height and gradient of fractal simplex noise in double precision in the direction
+/// `dir`, see `fractal_height`. `shape` returns the shaped noise and its derivative.
+fn fractal_height_and_gradient(
+    dir: &Vector3<f64>,
+    frequency: f32,
+    persistence: f32,
+    octaves: usize,
+    seed: u32,
+    footprint: f32,
+    shape: impl Fn(f32) -> (f32, f32),
+) -> (f32, Vector3<f32>) {
+    let mut height = 0.0;
+    let mut gradient = Vector3::zeros();
+    let mut max_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = f64::from(frequency);
+    for octave in 0..octaves {
+        let weight = octave_weight(frequency as f32, footprint) * amplitude;
+        if weight > 0.0 {
+            let p = dir * frequency + to_f64(&domain_offset(seed, octave as u32));
+            let (noise, noise_gradient) = noise::simplex_3d(&p);
+            let (shaped, slope) = shape(noise as f32);
+            height += shaped * weight;
+            gradient += to_f32(&noise_gradient) * (slope * weight * frequency as f32);
+        }
+        frequency *= 2.0;
+        max_amplitude += amplitude;
+        amplitude *= persistence;
+    }
+
+    (height / max_amplitude, gradient / max_amplitude)
+}
+
+/// Returns how much an octave of noise with the given frequency contributes to the terrain when it
+/// is sampled with the specified footprint. Octaves with a wavelength shorter than two samples
+/// would only alias and are left out, octaves with a wavelength shorter than four samples are
+/// faded out. A footprint of zero keeps every octave.
+fn octave_weight(frequency: f32, footprint: f32) -> f32 {
+    let t = (2.0 - 4.0 * frequency * footprint).max(0.0).min(1.0);
+    smoothstep(t)
+}
+
+/// Returns the number of octaves of turbulence that are visible at the specified footprint, the
+/// first octave is always kept.
+fn turbulence_octaves(frequency: f32, lacunarity: f32, octaves: u8, footprint: f32) -> u8 {
+    let mut frequency = frequency;
+    for octave in 1..octaves {
+        frequency *= lacunarity;
+        if octave_weight(frequency, footprint) <= 0.0 {
+            return octave;
+        }
+    }
+    octaves
+}
+
+/// The octave shape of `NoiseFBM` and its derivative
+fn fbm_shape(noise: f32) -> (f32, f32) {
+    ((1.0 - noise.abs()) * 2.0 - 1.0, -2.0 * noise.signum())
+}
+
+/// The octave shape of `NoiseRidge` and its derivative
+fn ridge_shape(noise: f32) -> (f32, f32) {
+    (noise, 1.0)
+}
+
+/// Constructs the rotation of a `Rotate` layer in double precision
+fn rotation_f64(axis: &[f32; 3], angle: f32) -> UnitQuaternion<f64> {
+    let axis = Unit::new_normalize(to_f64(&Vector3::new(axis[0], axis[1], axis[2])));
+    UnitQuaternion::from_axis_angle(&axis, f64::from(angle).to_radians())
+}
+
+fn to_f64(v: &Vector3<f32>) -> Vector3<f64> {
+    Vector3::new(f64::from(v.x), f64::from(v.y), f64::from(v.z))
+}
+
+fn to_f32(v: &Vector3<f64>) -> Vector3<f32> {
+    Vector3::new(v.x as f32, v.y as f32, v.z as f32)
+}
+
+/// Linearly blends two heights and their gradients by `t` which has a gradient of its own
+fn blend_with_gradient(
+    a: (f32, Vector3<f32>),
+    b: (f32, Vector3<f32>),
+    t: (f32, Vector3<f32>),
+) -> (f32, Vector3<f32>) {
+    (
+        lerp(a.0, b.0, t.0),
+        a.1 * (1.0 - t.0) + b.1 * t.0 + t.1 * (b.0 - a.0),
+    )
+}
+
+/// Applies `f` to every value in `values`
+fn map(mut values: Vec<f32>, f: impl Fn(f32) -> f32) -> Vec<f32> {
+    for value in values.iter_mut() {
+        *value = f(*value);
+    }
+    values
+}
+
+/// Replaces every value in `values` with the result of `f` applied to it and the value at the
+/// same index in `others`
+fn zip_with(values: &mut [f32], others: &[f32], f: impl Fn(f32, f32) -> f32) {
+    for (value, other) in values.iter_mut().zip(others) {
+        *value = f(*value, *other);
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Returns the index of the first of `len` sorted entries positioned above `value`, or `len` if
+/// there is no such entry.
+fn surrounding(value: f32, len: usize, position: impl Fn(usize) -> f32) -> usize {
+    (0..len).find(|&i| value < position(i)).unwrap_or(len)
+}
+
+/// Terraces `value` between the sorted `steps` (based on libnoise's terrace module). Returns the
+/// result and its derivative with respect to `value`.
+fn terrace(value: f32, steps: &[f32], invert: bool) -> (f32, f32) {
+    if steps.is_empty() {
+        return (value, 1.0);
+    }
+
+    let index = surrounding(value, steps.len(), |i| steps[i]);
+    let i0 = index.saturating_sub(1).min(steps.len() - 1);
+    let i1 = index.min(steps.len() - 1);
+    if i0 == i1 {
+        return (steps[i1], 0.0);
+    }
+
+    let (mut v0, mut v1) = (steps[i0], steps[i1]);
+    let mut alpha = (value - v0) / (v1 - v0);
+    let mut slope = 1.0 / (v1 - v0);
+    if invert {
+        alpha = 1.0 - alpha;
+        slope = -slope;
+        std::mem::swap(&mut v0, &mut v1);
+    }
+
+    (lerp(v0, v1, alpha * alpha), (v1 - v0) * 2.0 * alpha * slope)
+}
+
+/// Remaps `value` through a cubic spline through the sorted `points` using libnoise's cubic
+/// interpolation (based on libnoise's curve module). Unlike a Catmull-Rom spline the tangent at a
+/// point is the full difference of its neighbors rather than half of it. Returns the result and
+/// its derivative with respect to `value`.
+fn curve(value: f32, points: &[(f32, f32)]) -> (f32, f32) {
+    if points.is_empty() {
+        return (value, 1.0);
+    }
+
+    let last = points.len() as isize - 1;
+    let index = surrounding(value, points.len(), |i| points[i].0) as isize;
+    let point = |i: isize| points[i.max(0).min(last) as usize];
+    let (p0, p1, p2, p3) = (point(index - 2), point(index - 1), point(index), point(index + 1));
+    if (index - 1).max(0).min(last) == index.max(0).min(last) {
+        return (p1.1, 0.0);
+    }
+
+    let alpha = (value - p1.0) / (p2.0 - p1.0);
+    let p = (p3.1 - p2.1) - (p0.1 - p1.1);
+    let q = (p0.1 - p1.1) - p;
+    let r = p2.1 - p0.1;
+    let s = p1.1;
+    (
+        p * alpha * alpha * alpha + q * alpha * alpha + r * alpha + s,
+        (3.0 * p * alpha * alpha + 2.0 * q * alpha + r) / (p2.0 - p1.0),
+    )
+}
+
+/// Combines the seed of a planet with the seed of a single layer. Two zero seeds result in zero.
+pub fn combine_seeds(planet_seed: u32, layer_seed: u32) -> u32 {
+    planet_seed ^ layer_seed.wrapping_mul(0x9E37_79B9)
+}
+
+/// Returns a deterministic offset into the noise domain for a seed. Every octave of a fractal gets
+/// its own offset so the octaves do not line up. A seed of zero leaves the domain untouched.
+fn domain_offset(seed: u32, octave: u32) -> Vector3<f32> {
+    if seed == 0 {
+        return Vector3::zeros();
+    }
+
+    // The offsets lie within [0, 1), so they do not increase the magnitude of the sample position
+    // beyond that of the unit sphere scaled by the frequency, which would cost single precision
+    // samples their precision
+    let component = |index: u32| {
+        (hash(seed ^ hash(octave.wrapping_mul(3).wrapping_add(index))) >> 8) as f32 * (1.0 / 16_777_216.0)
+    };
+    Vector3::new(component(0), component(1), component(2))
+}
+
+/// Integer hash with good avalanche behavior (https://nullprogram.com/blog/2018/07/31/)
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+/// The contents of a terrain file: the height field of a planet and the colors and biomes used to
+/// paint it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Terrain {
+    /// Named layers that can be referenced from anywhere in the terrain using `ref: <name>`
+    #[serde(default)]
+    pub definitions: BTreeMap<String, TerrainLayer>,
+
+    pub height: TerrainLayer,
+
+    /// The colors used wherever the terrain is not covered by one of the `biomes`
+    #[serde(default)]
+    pub colors: ColorRamp,
+
+    #[serde(default)]
+    pub climate: Climate,
+
+    #[serde(default)]
+    pub biomes: Vec<Biome>,
+}
 
-        let mut entry_low = &mapping[0];
-        let mut entry_hi = &mapping[0];
-        for entry in &mapping {
-            entry_low = entry;
+/// A terrain file in the current format or in the format from before terrains had definitions,
+/// colors and biomes, which only contains the height layer.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TerrainFile {
+    Terrain(Terrain),
+    Height(TerrainLayer),
+}
+
+impl Terrain {
+    /// Constructs a context to evaluate the terrain of a planet with the given seed. A context can
+    /// be reused for any number of samples but can only be used by one thread at a time.
+    pub fn context(&self, planet_seed: u32) -> TerrainContext {
+        TerrainContext {
+            planet_seed,
+            footprint: 0.0,
+            names: self.definitions.keys().map(String::as_str).collect(),
+            layers: self.definitions.values().collect(),
+            cache: vec![None; self.definitions.len()],
+            batch_cache: vec![None; self.definitions.len()],
+            precise_cache: vec![None; self.definitions.len()],
+            gradient_cache: vec![None; self.definitions.len()],
+        }
+    }
 
-            let (entry_height, _entry_color) = entry;
-            if height < *entry_height {
-                break;
+    /// Reads a terrain from a YAML document and ensures it is valid. Errors contain the line of
+    /// the offending value in the document. Documents that only contain a layer, the format from
+    /// before terrains had definitions, colors and biomes, are read as the height of a terrain
+    /// with default colors.
+    pub fn from_yaml(source: &str) -> Result<Terrain, Box<std::error::Error>> {
+        let (mut terrain, root) = match serde_yaml::from_str(source) {
+            Ok(TerrainFile::Terrain(terrain)) => (terrain, ""),
+            Ok(TerrainFile::Height(height)) => {
+                let terrain = Terrain {
+                    definitions: BTreeMap::new(),
+                    height,
+                    colors: ColorRamp::default(),
+                    climate: Climate::default(),
+                    biomes: Vec::new(),
+                };
+                (terrain, "height")
             }
 
-            entry_hi = entry;
+            // Untagged enums do not tell why none of their variants matched, the error of the
+            // current format points at the offending line instead
+            Err(_) => (serde_yaml::from_str(source)?, ""),
+        };
+        validation::validate(&terrain, source, root)?;
+        terrain.assign_salts();
+        Ok(terrain)
+    }
+
+    /// Mixes the path of every layer with a seed into its seed, so layers with the same
+    /// parameters and seed at different places in the terrain produce different noise. Layers
+    /// reached through the same `ref` share the path of the definition.
+    fn assign_salts(&mut self) {
+        fn visit(layer: &mut TerrainLayer, path: u32) {
+            match layer {
+                TerrainLayer::NoiseCellular { salt, .. }
+                | TerrainLayer::NoiseFBM { salt, .. }
+                | TerrainLayer::NoiseRidge { salt, .. }
+                | TerrainLayer::NoiseSimplex { salt, .. }
+                | TerrainLayer::NoiseTurbulence { salt, .. } => *salt = path,
+                TerrainLayer::Craters(craters) => craters.salt = path,
+                _ => {}
+            }
+            for (i, child) in layer.children_mut().into_iter().enumerate() {
+                visit(child, hash(path ^ hash(i as u32 + 1)));
+            }
+        }
+
+        let name_hash = |name: &str| name.bytes().fold(0, |h, byte| hash(h ^ u32::from(byte)));
+        for (name, layer) in self.definitions.iter_mut() {
+            visit(layer, name_hash(&format!("definitions.{}", name)));
+        }
+        visit(&mut self.height, name_hash("height"));
+        if let Some(moisture) = &mut self.climate.moisture {
+            visit(moisture, name_hash("climate.moisture"));
+        }
+        if let Some(temperature) = &mut self.climate.temperature {
+            visit(temperature, name_hash("climate.temperature"));
+        }
+    }
+
+    /// Returns every heightmap used by the terrain.
+    pub fn heightmaps(&self) -> Vec<&Heightmap> {
+        fn visit<'a>(layer: &'a TerrainLayer, result: &mut Vec<&'a Heightmap>) {
+            if let TerrainLayer::Image { source, .. } = layer {
+                result.push(source);
+            }
+            for child in layer.children() {
+                visit(child, result);
+            }
+        }
+
+        // References are skipped, the definitions they refer to are visited on their own
+        let mut result = Vec::new();
+        let layers = self
+            .definitions
+            .values()
+            .chain(std::iter::once(&self.height))
+            .chain(self.climate.moisture.iter())
+            .chain(self.climate.temperature.iter());
+        for layer in layers {
+            visit(layer, &mut result);
+        }
+        result
+    }
+
+    pub fn compute_height(&self, dir: &Vector3<f32>, context: &mut TerrainContext) -> f32 {
+        self.height.compute_height(dir, context)
+    }
+
+    /// Computes the height of the terrain in the direction `dir` with the sampling domain in double
+    /// precision
+    pub fn compute_height_f64(&self, dir: &Vector3<f64>, context: &mut TerrainContext) -> f32 {
+        self.height.compute_height_f64(dir, context)
+    }
+
+    /// Computes the height of the terrain in the direction `dir` and its gradient
+    pub fn compute_height_and_gradient(&self, dir: &Vector3<f64>, context: &mut TerrainContext) -> (f32, Vector3<f32>) {
+        self.height.compute_height_and_gradient(dir, context)
+    }
+
+    /// Computes the height of the terrain for every direction in `dirs` at once
+    pub fn compute_heights(&self, dirs: &[Vector3<f32>], context: &mut TerrainContext) -> Vec<f32> {
+        self.height.compute_heights(dirs, context)
+    }
+
+    /// Computes the material of the terrain in the direction `dir` given its height and surface
+    /// normal.
+    pub fn compute_material(&self, dir: &Vector3<f32>, height: f32, normal: &Vector3<f32>, context: &mut TerrainContext) -> Material {
+        if self.biomes.is_empty() {
+            return self.colors.material_at(height);
         }
 
-        let (height_low, color_low) = entry_low;
-        let (height_hi, color_hi) = entry_hi;
+        let sample = self.climate.sample(dir, height, normal, context);
+        biome::material_at(&self.biomes, &self.colors, &sample)
+    }
+}
 
-        let a = f32::max(0.0, f32::min(1.0, (height - height_low) / (height_hi - height_low)));
+/// State shared by all layers while evaluating the terrain of a single planet.
+pub struct TerrainContext<'a> {
+    pub planet_seed: u32,
 
-        let color = (1.0 - a) * color_low + a * color_hi;
+    /// The distance between two neighbouring samples on the unit sphere
+    footprint: f32,
 
-        color
+    /// The names and layers of the definitions of the terrain, sorted by name
+    names: Vec<&'a str>,
+    layers: Vec<&'a TerrainLayer>,
+
+    /// For every definition the last direction and footprint it was evaluated at and its result.
+    /// The footprint is part of the key as layers like `Scale` change it for their value.
+    cache: Vec<Option<(Vector3<f32>, f32, f32)>>,
+
+    /// For every definition the last batch of directions and footprint it was evaluated at and its
+    /// results
+    batch_cache: Vec<Option<(Vec<Vector3<f32>>, f32, Vec<f32>)>>,
+
+    /// For every definition the last double precision direction and footprint it was evaluated at
+    /// and its result
+    precise_cache: Vec<Option<(Vector3<f64>, f32, f32)>>,
+
+    /// For every definition the last direction and footprint its gradient was evaluated at and its
+    /// result
+    gradient_cache: Vec<Option<(Vector3<f64>, f32, f32, Vector3<f32>)>>,
+}
+
+impl<'a> TerrainContext<'a> {
+    /// Sets the distance between two neighbouring samples on the unit sphere. Octaves of fractal
+    /// noise that are too fine to be represented by the samples are faded out, a footprint of zero
+    /// keeps every octave.
+    pub fn set_footprint(&mut self, footprint: f32) {
+        self.footprint = footprint;
+    }
+
+    /// Evaluates `f` with the footprint scaled by `factor`, used by layers that scale the domain.
+    fn with_scaled_footprint<T>(&mut self, factor: f32, f: impl FnOnce(&mut Self) -> T) -> T {
+        let footprint = self.footprint;
+        self.footprint *= factor.abs();
+        let result = f(self);
+        self.footprint = footprint;
+        result
     }
-}
\ No newline at end of file
+
+    /// Evaluates the definition with the specified name. The result is cached so definitions that
+    /// are referenced multiple times are computed only once per sample.
+    fn compute_definition(&mut self, name: &str, dir: &Vector3<f32>) -> f32 {
+        let index = match self.names.binary_search(&name) {
+            Ok(index) => index,
+            Err(_) => return 0.0, // Rejected by `validation::validate`
+        };
+
+        if let Some((cached_dir, footprint, height)) = self.cache[index] {
+            if cached_dir == *dir && footprint == self.footprint {
+                return height;
+            }
+        }
+
+        let layer = self.layers[index];
+        let height = layer.compute_height(dir, self);
+        self.cache[index] = Some((*dir, self.footprint, height));
+        height
+    }
+
+    /// Evaluates the definition with the specified name in double precision, like
+    /// `compute_definition` the result is cached.
+    fn compute_definition_f64(&mut self, name: &str, dir: &Vector3<f64>) -> f32 {
+        let index = match self.names.binary_search(&name) {
+            Ok(index) => index,
+            Err(_) => return 0.0, // Rejected by `validation::validate`
+        };
+
+        if let Some((cached_dir, footprint, height)) = self.precise_cache[index] {
+            if cached_dir == *dir && footprint == self.footprint {
+                return height;
+            }
+        }
+
+        let layer = self.layers[index];
+        let height = layer.compute_height_f64(dir, self);
+        self.precise_cache[index] = Some((*dir, self.footprint, height));
+        height
+    }
+
+    /// Evaluates the definition with the specified name and its gradient, like
+    /// `compute_definition` the result is cached.
+    fn compute_definition_and_gradient(&mut self, name: &str, dir: &Vector3<f64>) -> (f32, Vector3<f32>) {
+        let index = match self.names.binary_search(&name) {
+            Ok(index) => index,
+            Err(_) => return (0.0, Vector3::zeros()), // Rejected by `validation::validate`
+        };
+
+        if let Some((cached_dir, footprint, height, gradient)) = self.gradient_cache[index] {
+            if cached_dir == *dir && footprint == self.footprint {
+                return (height, gradient);
+            }
+        }
+
+        let layer = self.layers[index];
+        let (height, gradient) = layer.compute_height_and_gradient(dir, self);
+        self.gradient_cache[index] = Some((*dir, self.footprint, height, gradient));
+        (height, gradient)
+    }
+
+    /// Evaluates the definition with the specified name for a batch of directions, like
+    /// `compute_definition` the results are cached.
+    fn compute_definitions(&mut self, name: &str, dirs: &[Vector3<f32>]) -> Vec<f32> {
+        let index = match self.names.binary_search(&name) {
+            Ok(index) => index,
+            Err(_) => return vec![0.0; dirs.len()], // Rejected by `validation::validate`
+        };
+
+        if let Some((cached_dirs, footprint, heights)) = &self.batch_cache[index] {
+            if cached_dirs.as_slice() == dirs && *footprint == self.footprint {
+                return heights.clone();
+            }
+        }
+
+        let layer = self.layers[index];
+        let heights = layer.compute_heights(dirs, self);
+        self.batch_cache[index] = Some((dirs.to_vec(), self.footprint, heights.clone()));
+        heights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directions spread over the whole sphere
+    fn directions() -> Vec<Vector3<f32>> {
+        (0..300)
+            .map(|i| {
+                let i = i as f32;
+                Vector3::new(
+                    (i * 0.731).sin(),
+                    (i * 1.379).cos(),
+                    (i * 0.291).sin() * 1.3,
+                )
+                .normalize()
+            })
+            .collect()
+    }
+
+    /// Asserts that `compute_heights` and `compute_height` agree within `tolerance`, both with
+    /// every octave and with fine octaves faded out by the footprint
+    fn assert_batch_matches(terrain: &Terrain, tolerance: f32) {
+        let dirs = directions();
+        for &footprint in [0.0, 0.002].iter() {
+            let mut context = terrain.context(7);
+            context.set_footprint(footprint);
+            let heights = terrain.compute_heights(&dirs, &mut context);
+
+            let mut context = terrain.context(7);
+            context.set_footprint(footprint);
+            for (dir, height) in dirs.iter().zip(heights.iter()) {
+                let expected = terrain.compute_height(dir, &mut context);
+                assert!(
+                    (height - expected).abs() <= tolerance,
+                    "{} instead of {} in the direction {:?}",
+                    height,
+                    expected,
+                    dir
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn batched_heights_match_resources_terrain() {
+        let terrain = Terrain::from_yaml(include_str!("../../resources/terrain.yaml")).unwrap();
+        assert_batch_matches(&terrain, 1.0);
+    }
+
+    #[test]
+    fn batched_heights_match_every_layer() {
+        let path =
+            std::env::temp_dir().join(format!("omniverse-terrain-{}.png", std::process::id()));
+        let pixels: Vec<u8> = (0..32 * 16).map(|i| (i * 37 % 256) as u8).collect();
+        image::save_buffer(&path, &pixels, 32, 16, image::ColorType::Gray(8)).unwrap();
+
+        let source = format!(
+            r#"
+definitions:
+  base:
+    noiseSimplex: {{ seed: 3 }}
+height:
+  add:
+    - constant: 0.1
+    - multiply:
+      - ref: base
+      - noiseFBM: {{ frequency: 2, persistence: 0.5, octaves: 6 }}
+    - clamp: {{ min: -0.3, max: 0.3, value: {{ noiseRidge: {{ frequency: 3, persistence: 0.5, octaves: 4 }} }} }}
+    - min:
+      - noiseCellular: {{ distance_fn: Euclidean, return_type: Distance, jitter: 0.4 }}
+      - abs: {{ ref: base }}
+    - max:
+      - negate: {{ noiseTurbulence: {{ freq: 2, lacunarity: 2, gain: 0.5, octaves: 5 }} }}
+      - pow: {{ exponent: 1.5, value: {{ ref: base }} }}
+    - terrace: {{ steps: [-0.5, 0.0, 0.4], invert: true, value: {{ ref: base }} }}
+    - curve: {{ points: [[-1, -1], [-0.2, 0.1], [0.3, 0.2], [1, 1]], value: {{ ref: base }} }}
+    - select:
+        mask: {{ noiseSimplex: {{ seed: 5 }} }}
+        a: {{ constant: -0.2 }}
+        b: {{ scale: {{ factor: 3, value: {{ ref: base }} }} }}
+        threshold: 0.1
+        falloff: 0.2
+    - blend:
+        mask: {{ noiseSimplex: {{ seed: 6 }} }}
+        a: {{ translate: {{ offset: [1, 2, 3], value: {{ ref: base }} }} }}
+        b: {{ rotate: {{ axis: [0, 1, 1], angle: 30, value: {{ ref: base }} }} }}
+    - warp:
+        strength: 0.1
+        x: {{ noiseSimplex: {{ seed: 7 }} }}
+        y: {{ noiseSimplex: {{ seed: 8 }} }}
+        z: {{ noiseSimplex: {{ seed: 9 }} }}
+        value: {{ ref: base }}
+    - craters: {{ min_radius: 0.01, max_radius: 0.1, density: 20, depth: 0.2 }}
+    - image: {{ source: {{ equirectangular: '{}' }}, filter: bicubic, scale: 0.5 }}
+"#,
+            path.display()
+        );
+        let terrain = Terrain::from_yaml(&source);
+        std::fs::remove_file(&path).unwrap();
+        assert_batch_matches(&terrain.unwrap(), 1.0e-4);
+    }
+
+    #[test]
+    fn layers_with_the_same_seed_differ() {
+        let terrain =
+            Terrain::from_yaml("height: { add: [ { noiseSimplex: {} }, { noiseSimplex: {} } ] }")
+                .unwrap();
+        let children = terrain.height.children();
+        let mut context = terrain.context(0);
+        let dir = Vector3::new(0.3, -0.5, 0.8).normalize();
+        let a = children[0].compute_height(&dir, &mut context);
+        let b = children[1].compute_height(&dir, &mut context);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let terrain = Terrain::from_yaml(
+            r#"
+height:
+  add:
+    - multiply:
+      - noiseFBM: { frequency: 1, persistence: 0.5, octaves: 3 }
+      - noiseSimplex: { seed: 2 }
+    - clamp: { min: -0.3, max: 0.3, value: { noiseSimplex: { seed: 3 } } }
+    - warp:
+        strength: 0.2
+        x: { noiseSimplex: { seed: 4 } }
+        y: { noiseSimplex: { seed: 5 } }
+        z: { noiseSimplex: { seed: 6 } }
+        value: { noiseFBM: { frequency: 1.5, persistence: 0.5, octaves: 2 } }
+"#,
+        )
+        .unwrap();
+        let clamped = terrain.height.children()[1].children()[0];
+        let mut context = terrain.context(7);
+        let eps = 1.0e-4;
+        for dir in directions().iter().map(to_f64) {
+            // Finite differences are meaningless where the clamp cuts off its value
+            let value = clamped.compute_height_f64(&dir, &mut context);
+            if (value.abs() - 0.3).abs() < 0.02 {
+                continue;
+            }
+
+            let (_, gradient) = terrain.compute_height_and_gradient(&dir, &mut context);
+            for axis in 0..3 {
+                let mut step = Vector3::zeros();
+                step[axis] = eps;
+                let high = terrain.compute_height_f64(&(dir + step), &mut context);
+                let low = terrain.compute_height_f64(&(dir - step), &mut context);
+                let expected = (f64::from(high) - f64::from(low)) / (2.0 * eps);
+                assert!(
+                    (f64::from(gradient[axis]) - expected).abs() <= 1.0e-2 * (1.0 + expected.abs()),
+                    "{:?} instead of {} along axis {} in the direction {:?}",
+                    gradient,
+                    expected,
+                    axis,
+                    dir
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bare_layers_are_read_as_height() {
+        let layer = "add: [ { constant: 0.5 }, { noiseSimplex: { seed: 2 } } ]";
+        let bare = Terrain::from_yaml(layer).unwrap();
+        let wrapped = Terrain::from_yaml(&format!("height: {{ {} }}", layer)).unwrap();
+        assert!(bare.definitions.is_empty() && bare.biomes.is_empty());
+
+        let mut bare_context = bare.context(7);
+        let mut wrapped_context = wrapped.context(7);
+        for dir in directions() {
+            assert_eq!(
+                bare.compute_height(&dir, &mut bare_context),
+                wrapped.compute_height(&dir, &mut wrapped_context)
+            );
+        }
+
+        let error = Terrain::from_yaml("add:\n  - constant: 1\n  - ref: missing\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "height.add[1].ref: refers to the unknown definition 'missing' at line 3"
+        );
+    }
+
+    /// Asserts that `actual` is within a small tolerance of `expected`
+    fn assert_close(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1.0e-6 && (actual.1 - expected.1).abs() < 1.0e-6,
+            "{:?} instead of {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn terrace_at_known_inputs() {
+        let steps = [0.0, 0.5, 1.0];
+        assert_close(terrace(-1.0, &steps, false), (0.0, 0.0));
+        assert_close(terrace(0.25, &steps, false), (0.125, 1.0));
+        assert_close(terrace(0.5, &steps, false), (0.5, 0.0));
+        assert_close(terrace(2.0, &steps, false), (1.0, 0.0));
+        assert_close(terrace(0.25, &steps, true), (0.375, 1.0));
+        assert_close(terrace(0.5, &steps, true), (0.5, 2.0));
+    }
+
+    #[test]
+    fn curve_at_and_between_points() {
+        let points = [(-1.0, -1.0), (0.0, 0.5), (1.0, 0.0), (2.0, 1.0)];
+        for &(input, output) in points.iter() {
+            assert_eq!(curve(input, &points).0, output);
+        }
+        assert_close(curve(0.5, &points), (0.3125, -1.125));
+
+        // Outside of the points the curve is flat
+        assert_close(curve(-2.0, &points), (-1.0, 0.0));
+        assert_close(curve(3.0, &points), (1.0, 0.0));
+    }
+
+    #[test]
+    fn select_at_known_masks() {
+        let masks = [
+            (-1.0, -1.0),
+            (-0.5, -1.0),
+            (0.0, 0.0),
+            (0.25, 0.6875),
+            (0.5, 1.0),
+        ];
+        for &(mask, expected) in masks.iter() {
+            let source = format!(
+                "height: {{ select: {{ mask: {{ constant: {} }}, a: {{ constant: -1 }}, b: {{ constant: 1 }}, threshold: 0, falloff: 0.5 }} }}",
+                mask
+            );
+            let terrain = Terrain::from_yaml(&source).unwrap();
+            let mut context = terrain.context(0);
+            let height = terrain.compute_height(&Vector3::new(0.0, 0.0, 1.0), &mut context);
+            assert!(
+                (height - expected).abs() < 1.0e-6,
+                "{} instead of {} for a mask of {}",
+                height,
+                expected,
+                mask
+            );
+        }
+    }
+
+    #[test]
+    fn constant_warps_translate() {
+        let source = "
+definitions:
+  base: { noiseSimplex: {} }
+height:
+  warp:
+    strength: 2
+    x: { constant: 0.1 }
+    y: { constant: -0.2 }
+    z: { constant: 0.3 }
+    value: { ref: base }
+";
+        let terrain = Terrain::from_yaml(source).unwrap();
+        let base = &terrain.definitions["base"];
+        let mut context = terrain.context(7);
+        let offset = Vector3::new(0.2, -0.4, 0.6);
+        for dir in directions() {
+            let height = terrain.compute_height(&dir, &mut context);
+            let expected = base.compute_height(&(dir + offset), &mut context);
+            assert!(
+                (height - expected).abs() < 1.0e-6,
+                "{} instead of {}",
+                height,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn transforms_move_the_domain_of_their_value() {
+        let source = "
+definitions:
+  base: { noiseSimplex: {} }
+height:
+  add:
+    - scale: { factor: 2, value: { ref: base } }
+    - translate: { offset: [0.5, -1, 2], value: { ref: base } }
+    - rotate: { axis: [0, 0, 1], angle: 90, value: { ref: base } }
+";
+        let terrain = Terrain::from_yaml(source).unwrap();
+        let base = &terrain.definitions["base"];
+        let layers = terrain.height.children();
+        let mut context = terrain.context(7);
+        for dir in directions() {
+            let transformed = [
+                dir * 2.0,
+                dir + Vector3::new(0.5, -1.0, 2.0),
+                Vector3::new(-dir.y, dir.x, dir.z),
+            ];
+            for (layer, position) in layers.iter().zip(transformed.iter()) {
+                let height = layer.compute_height(&dir, &mut context);
+                let expected = base.compute_height(position, &mut context);
+                assert!(
+                    (height - expected).abs() < 1.0e-5,
+                    "{} instead of {}",
+                    height,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shared_definitions_are_evaluated_once() {
+        let source = "
+definitions:
+  base: { noiseSimplex: {} }
+height: { add: [ { ref: base }, { negate: { ref: base } }, { ref: base } ] }
+";
+        let terrain = Terrain::from_yaml(source).unwrap();
+        let dir = Vector3::new(0.0, 0.0, 1.0);
+
+        // A cached result is returned by every reference instead of evaluating the definition
+        let mut context = terrain.context(7);
+        context.cache[0] = Some((dir, 0.0, 0.25));
+        assert_eq!(terrain.compute_height(&dir, &mut context), 0.25);
+        context.precise_cache[0] = Some((to_f64(&dir), 0.0, 0.25));
+        assert_eq!(
+            terrain.compute_height_f64(&to_f64(&dir), &mut context),
+            0.25
+        );
+        context.batch_cache[0] = Some((vec![dir], 0.0, vec![0.25]));
+        assert_eq!(terrain.compute_heights(&[dir], &mut context), vec![0.25]);
+    }
+
+    #[test]
+    fn cached_definitions_depend_on_the_footprint() {
+        let source = "
+definitions:
+  base: { noiseFBM: { frequency: 4, persistence: 0.8, octaves: 12 } }
+height:
+  add:
+    - ref: base
+    - scale: { factor: 2, value: { translate: { offset: [0, 0, -1], value: { ref: base } } } }
+";
+        let terrain = Terrain::from_yaml(source).unwrap();
+        let base = &terrain.definitions["base"];
+        let dir = Vector3::new(0.0, 0.0, 1.0);
+        let mut context = terrain.context(7);
+        context.set_footprint(0.01);
+        let height = terrain.compute_height(&dir, &mut context);
+        let fine = base.compute_height(&dir, &mut context);
+        context.set_footprint(0.02);
+        let coarse = base.compute_height(&dir, &mut context);
+        assert_ne!(fine, coarse);
+        assert!((height - (fine + coarse)).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn cyclic_definitions_are_rejected() {
+        let source = "
+definitions:
+  a: { add: [ { constant: 1 }, { ref: b } ] }
+  b: { ref: a }
+height: { ref: a }
+";
+        assert!(Terrain::from_yaml(source).is_err());
+    }
+
+    #[test]
+    fn octaves_fade_out_between_four_and_two_footprints() {
+        let footprint = 0.01;
+        for &wavelength in [1.0, 0.1, 0.045].iter() {
+            assert_eq!(octave_weight(1.0 / wavelength, footprint), 1.0);
+        }
+        for &wavelength in [0.0195, 0.001].iter() {
+            assert_eq!(octave_weight(1.0 / wavelength, footprint), 0.0);
+        }
+
+        let mut previous = 1.0;
+        for i in 1..20 {
+            let wavelength = 0.04 - 0.001 * i as f32;
+            let weight = octave_weight(1.0 / wavelength, footprint);
+            assert!(weight < previous && weight > 0.0);
+            previous = weight;
+        }
+
+        assert_eq!(octave_weight(1.0e6, 0.0), 1.0);
+        assert_eq!(turbulence_octaves(1.0, 2.0, 10, footprint), 6);
+    }
+}
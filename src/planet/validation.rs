@@ -0,0 +1,651 @@
+use crate::planet::biome::Condition;
+use crate::planet::color_ramp::ColorRamp;
+use crate::planet::craters::{Craters, MAX_EXPONENT, MAX_LEVELS};
+use crate::planet::terrain::{Terrain, TerrainLayer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// A value in a terrain file that deserialized correctly but makes no sense.
+#[derive(Debug)]
+pub struct ValidationError {
+    /// The path of the offending node in the terrain file, e.g. `height.add[1].noiseFBM.octaves`
+    pub path: String,
+
+    /// The line of the offending node, or of its closest parent if the node itself is not
+    /// present in the file
+    pub line: Option<usize>,
+
+    pub message: String,
+}
+
+/// All validation errors of a terrain file.
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}: {} at line {}", self.path, self.message, line),
+            None => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+impl std::error::Error for ValidationErrors {}
+
+/// Checks the terrain for nonsensical values and for references to unknown definitions or
+/// references that form a cycle. `source` is the YAML the terrain was read from, it is used to
+/// report the line of every error. `root` is the path of the document within the terrain, `height`
+/// for documents that only contain the height layer.
+pub fn validate(terrain: &Terrain, source: &str, root: &str) -> Result<(), ValidationErrors> {
+    let mut validator = Validator {
+        errors: Vec::new(),
+        definitions: &terrain.definitions,
+        definition: None,
+    };
+    for (name, layer) in terrain.definitions.iter() {
+        validator.definition = Some(name);
+        validator.layer(layer, &child("definitions", name));
+    }
+    validator.definition = None;
+    validator.layer(&terrain.height, "height");
+    validator.color_ramp(&terrain.colors, "colors");
+    if let Some(moisture) = &terrain.climate.moisture {
+        validator.layer(moisture, "climate.moisture");
+    }
+    if let Some(temperature) = &terrain.climate.temperature {
+        validator.layer(temperature, "climate.temperature");
+    }
+    for (i, biome) in terrain.biomes.iter().enumerate() {
+        let path = index("biomes", i);
+        let conditions = [
+            ("latitude", &biome.latitude),
+            ("height", &biome.height),
+            ("slope", &biome.slope),
+            ("moisture", &biome.moisture),
+            ("temperature", &biome.temperature),
+        ];
+        for (name, condition) in conditions.iter() {
+            if let Some(condition) = condition {
+                validator.condition(condition, &child(&path, name));
+            }
+        }
+        validator.color_ramp(&biome.colors, &child(&path, "colors"));
+    }
+
+    if validator.errors.is_empty() {
+        return Ok(());
+    }
+
+    let lines = line_numbers(source, root);
+    for error in validator.errors.iter_mut() {
+        error.line = line_of(&lines, &error.path);
+    }
+    Err(ValidationErrors(validator.errors))
+}
+
+/// Collects the errors found while walking a terrain
+struct Validator<'a> {
+    errors: Vec<ValidationError>,
+    definitions: &'a BTreeMap<String, TerrainLayer>,
+
+    /// The name of the definition that is being walked, if any
+    definition: Option<&'a str>,
+}
+
+impl<'a> Validator<'a> {
+    fn check(&mut self, valid: bool, path: String, message: &str) {
+        if !valid {
+            self.errors.push(ValidationError {
+                path,
+                line: None,
+                message: message.to_string(),
+            });
+        }
+    }
+
+    fn finite(&mut self, value: f32, path: String) {
+        self.check(value.is_finite(), path, "must be a finite number");
+    }
+
+    fn positive(&mut self, value: f32, path: String) {
+        self.check(
+            value.is_finite() && value > 0.0,
+            path,
+            "must be a positive number",
+        );
+    }
+
+    fn layers(&mut self, layers: &[TerrainLayer], path: &str) {
+        self.check(
+            !layers.is_empty(),
+            path.to_string(),
+            "must contain at least one layer",
+        );
+        for (i, layer) in layers.iter().enumerate() {
+            self.layer(layer, &index(path, i));
+        }
+    }
+
+    fn layer(&mut self, layer: &TerrainLayer, path: &str) {
+        match layer {
+            TerrainLayer::Add(children) => self.layers(children, &child(path, "add")),
+            TerrainLayer::Multiply(children) => self.layers(children, &child(path, "multiply")),
+            TerrainLayer::Min(children) => self.layers(children, &child(path, "min")),
+            TerrainLayer::Max(children) => self.layers(children, &child(path, "max")),
+            TerrainLayer::Constant(value) => self.finite(*value, child(path, "constant")),
+            TerrainLayer::Clamp { min, max, value } => {
+                let path = child(path, "clamp");
+                if let Some(min) = min {
+                    self.finite(*min, child(&path, "min"));
+                }
+                if let Some(max) = max {
+                    self.finite(*max, child(&path, "max"));
+                }
+                if let (Some(min), Some(max)) = (min, max) {
+                    self.check(
+                        min <= max,
+                        child(&path, "min"),
+                        "must not be larger than max",
+                    );
+                }
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Abs(value) => self.layer(value, &child(path, "abs")),
+            TerrainLayer::Negate(value) => self.layer(value, &child(path, "negate")),
+            TerrainLayer::Pow { exponent, value } => {
+                let path = child(path, "pow");
+                self.check(
+                    exponent.is_finite() && *exponent >= 0.0,
+                    child(&path, "exponent"),
+                    "must be a finite number that is not negative, negative powers of zero are infinite",
+                );
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Terrace { steps, value, .. } => {
+                let path = child(path, "terrace");
+                for (i, step) in steps.iter().enumerate() {
+                    self.finite(*step, index(&child(&path, "steps"), i));
+                }
+                let sorted = steps.windows(2).all(|w| w[0] <= w[1]);
+                self.check(sorted, child(&path, "steps"), "must be sorted");
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Curve { points, value } => {
+                let path = child(path, "curve");
+                for (i, (input, output)) in points.iter().enumerate() {
+                    let point = index(&child(&path, "points"), i);
+                    self.finite(*input, index(&point, 0));
+                    self.finite(*output, index(&point, 1));
+                }
+                let sorted = points.windows(2).all(|w| w[0].0 < w[1].0);
+                self.check(
+                    sorted,
+                    child(&path, "points"),
+                    "must be sorted by their input and every input must be unique",
+                );
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Select {
+                mask,
+                a,
+                b,
+                threshold,
+                falloff,
+            } => {
+                let path = child(path, "select");
+                self.finite(*threshold, child(&path, "threshold"));
+                self.check(
+                    falloff.is_finite() && *falloff >= 0.0,
+                    child(&path, "falloff"),
+                    "must not be negative",
+                );
+                self.layer(mask, &child(&path, "mask"));
+                self.layer(a, &child(&path, "a"));
+                self.layer(b, &child(&path, "b"));
+            }
+            TerrainLayer::Blend { mask, a, b } => {
+                let path = child(path, "blend");
+                self.layer(mask, &child(&path, "mask"));
+                self.layer(a, &child(&path, "a"));
+                self.layer(b, &child(&path, "b"));
+            }
+            TerrainLayer::Warp {
+                strength,
+                x,
+                y,
+                z,
+                value,
+            } => {
+                let path = child(path, "warp");
+                self.finite(*strength, child(&path, "strength"));
+                self.layer(x, &child(&path, "x"));
+                self.layer(y, &child(&path, "y"));
+                self.layer(z, &child(&path, "z"));
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Scale { factor, value } => {
+                let path = child(path, "scale");
+                self.check(
+                    factor.is_finite() && *factor != 0.0,
+                    child(&path, "factor"),
+                    "must be a finite number other than zero",
+                );
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Translate { offset, value } => {
+                let path = child(path, "translate");
+                for (i, component) in offset.iter().enumerate() {
+                    self.finite(*component, index(&child(&path, "offset"), i));
+                }
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Rotate { axis, angle, value } => {
+                let path = child(path, "rotate");
+                let length_squared: f32 = axis.iter().map(|c| c * c).sum();
+                self.check(
+                    length_squared.is_finite() && length_squared > 0.0,
+                    child(&path, "axis"),
+                    "must be a finite vector with a non-zero length",
+                );
+                self.finite(*angle, child(&path, "angle"));
+                self.layer(value, &child(&path, "value"));
+            }
+            TerrainLayer::Ref(name) => self.reference(name, path),
+            TerrainLayer::Craters(craters) => self.craters(craters, &child(path, "craters")),
+            TerrainLayer::Image { scale, .. } => {
+                self.finite(*scale, child(&child(path, "image"), "scale"));
+            }
+            TerrainLayer::NoiseCellular { jitter, .. } => {
+                self.finite(*jitter, child(&child(path, "noiseCellular"), "jitter"));
+            }
+            TerrainLayer::NoiseFBM {
+                frequency,
+                persistence,
+                octaves,
+                ..
+            } => self.fractal(*frequency, *persistence, *octaves, &child(path, "noiseFBM")),
+            TerrainLayer::NoiseRidge {
+                frequency,
+                persistence,
+                octaves,
+                ..
+            } => self.fractal(
+                *frequency,
+                *persistence,
+                *octaves,
+                &child(path, "noiseRidge"),
+            ),
+            TerrainLayer::NoiseSimplex { .. } => {}
+            TerrainLayer::NoiseTurbulence {
+                freq,
+                lacunarity,
+                gain,
+                octaves,
+                ..
+            } => {
+                let path = child(path, "noiseTurbulence");
+                self.positive(*freq, child(&path, "freq"));
+                self.finite(*lacunarity, child(&path, "lacunarity"));
+                self.finite(*gain, child(&path, "gain"));
+                self.check(*octaves > 0, child(&path, "octaves"), "must be at least 1");
+            }
+        }
+    }
+
+    fn reference(&mut self, name: &str, path: &str) {
+        let path = child(path, "ref");
+        let definitions = self.definitions;
+        match (definitions.get(name), self.definition) {
+            (None, _) => self.check(
+                false,
+                path,
+                &format!("refers to the unknown definition '{}'", name),
+            ),
+            (Some(definition), Some(current)) => {
+                let cycle =
+                    name == current || refers_to(definitions, definition, current, &mut Vec::new());
+                self.check(
+                    !cycle,
+                    path,
+                    &format!("refers back to the definition '{}' it is part of", current),
+                );
+            }
+            (Some(_), None) => {}
+        }
+    }
+
+    fn fractal(&mut self, frequency: f32, persistence: f32, octaves: usize, path: &str) {
+        self.positive(frequency, child(path, "frequency"));
+        self.finite(persistence, child(path, "persistence"));
+        self.check(octaves > 0, child(path, "octaves"), "must be at least 1");
+    }
+
+    fn craters(&mut self, craters: &Craters, path: &str) {
+        self.positive(craters.min_radius, child(path, "min_radius"));
+        self.check(
+            craters.max_radius.is_finite() && craters.max_radius >= craters.min_radius,
+            child(path, "max_radius"),
+            "must not be smaller than min_radius",
+        );
+        self.check(
+            craters.exponent.is_finite() && craters.exponent <= MAX_EXPONENT,
+            child(path, "exponent"),
+            &format!("must not be larger than {}", MAX_EXPONENT),
+        );
+
+        // Radii that make no sense are reported above
+        let levels = (craters.max_radius / craters.min_radius).log2();
+        self.check(
+            !levels.is_finite() || levels < MAX_LEVELS as f32,
+            child(path, "min_radius"),
+            &format!(
+                "must be larger than max_radius / 2^{}, craters have at most {} size classes",
+                MAX_LEVELS, MAX_LEVELS
+            ),
+        );
+        self.check(
+            craters.density.is_finite() && craters.density >= 0.0,
+            child(path, "density"),
+            "must not be negative",
+        );
+        self.finite(craters.depth, child(path, "depth"));
+        self.finite(craters.rim_height, child(path, "rim_height"));
+        self.finite(craters.peak_height, child(path, "peak_height"));
+        self.positive(craters.ejecta, child(path, "ejecta"));
+    }
+
+    fn color_ramp(&mut self, ramp: &ColorRamp, path: &str) {
+        for (i, stop) in ramp.stops.iter().enumerate() {
+            let stop_path = index(&child(path, "stops"), i);
+            self.finite(stop.height, child(&stop_path, "height"));
+            self.finite(stop.alpha, child(&stop_path, "alpha"));
+            self.finite(stop.roughness, child(&stop_path, "roughness"));
+        }
+        let sorted = ramp.stops.windows(2).all(|w| w[0].height <= w[1].height);
+        self.check(sorted, child(path, "stops"), "must be sorted by height");
+    }
+
+    fn condition(&mut self, condition: &Condition, path: &str) {
+        if let Some(min) = condition.min {
+            self.finite(min, child(path, "min"));
+        }
+        if let Some(max) = condition.max {
+            self.finite(max, child(path, "max"));
+        }
+        if let (Some(min), Some(max)) = (condition.min, condition.max) {
+            self.check(
+                min <= max,
+                child(path, "min"),
+                "must not be larger than max",
+            );
+        }
+        self.check(
+            condition.blend.is_finite() && condition.blend >= 0.0,
+            child(path, "blend"),
+            "must not be negative",
+        );
+    }
+}
+
+/// Returns whether `layer` refers to the definition `name`, directly or through other
+/// definitions. `visited` contains the definitions that were already followed.
+fn refers_to<'a>(
+    definitions: &'a BTreeMap<String, TerrainLayer>,
+    layer: &'a TerrainLayer,
+    name: &str,
+    visited: &mut Vec<&'a str>,
+) -> bool {
+    if let TerrainLayer::Ref(reference) = layer {
+        if reference == name {
+            return true;
+        }
+        if !visited.contains(&reference.as_str()) {
+            visited.push(reference);
+            if let Some(definition) = definitions.get(reference) {
+                if refers_to(definitions, definition, name, visited) {
+                    return true;
+                }
+            }
+        }
+    }
+    layer
+        .children()
+        .into_iter()
+        .any(|child| refers_to(definitions, child, name, visited))
+}
+
+/// Returns the path of the field `name` of the node at `path`
+fn child(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// Returns the path of the element `i` of the sequence at `path`
+fn index(path: &str, i: usize) -> String {
+    format!("{}[{}]", path, i)
+}
+
+/// Returns the line of the node at `path`. If the node is not present in the file, for instance
+/// because it has a default value, the line of its closest parent is returned instead.
+fn line_of(lines: &HashMap<String, usize>, path: &str) -> Option<usize> {
+    let mut path = path;
+    loop {
+        if let Some(line) = lines.get(path) {
+            return Some(*line);
+        }
+        match path.rfind(|c: char| c == '.' || c == '[') {
+            Some(index) => path = &path[..index],
+            None => return None,
+        }
+    }
+}
+
+/// Maps the path of every node in the YAML document, which is found at `root`, to the line it
+/// starts on. serde_yaml only reports the location of syntax and type errors, not of values that
+/// deserialize fine but are rejected afterwards, so the document is parsed again with yaml-rust,
+/// the parser serde_yaml is built on.
+fn line_numbers(source: &str, root: &str) -> HashMap<String, usize> {
+    let mut collector = LineCollector {
+        root: root.to_string(),
+        lines: HashMap::new(),
+        stack: Vec::new(),
+    };
+
+    // The document was already parsed successfully by serde, errors only result in missing lines
+    let _ = Parser::new(source.chars()).load(&mut collector, false);
+    collector.lines
+}
+
+/// A mapping or sequence the `LineCollector` is currently in
+enum Frame {
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, index: usize },
+}
+
+/// Tracks the path of the current node while walking the events of a YAML parser
+struct LineCollector {
+    /// The path of the document itself
+    root: String,
+    lines: HashMap<String, usize>,
+    stack: Vec<Frame>,
+}
+
+impl LineCollector {
+    /// Registers the start of a node that is not the key of a mapping and returns its path
+    fn node(&mut self, marker: Marker) -> String {
+        let path = match self.stack.last_mut() {
+            None => self.root.clone(),
+            Some(Frame::Mapping { path, key }) => child(path, &key.take().unwrap_or_default()),
+            Some(Frame::Sequence { path, index: next }) => {
+                *next += 1;
+                index(path, *next - 1)
+            }
+        };
+        self.lines
+            .entry(path.clone())
+            .or_insert_with(|| marker.line());
+        path
+    }
+}
+
+impl MarkedEventReceiver for LineCollector {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                if let Some(Frame::Mapping { key, .. }) = self.stack.last_mut() {
+                    if key.is_none() {
+                        *key = Some(value);
+                        return;
+                    }
+                }
+                self.node(marker);
+            }
+            Event::Alias(_) => {
+                self.node(marker);
+            }
+            Event::SequenceStart(_) => {
+                let path = self.node(marker);
+                self.stack.push(Frame::Sequence { path, index: 0 });
+            }
+            Event::MappingStart(_) => {
+                let path = self.node(marker);
+                self.stack.push(Frame::Mapping { path, key: None });
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path, line and message of every error in the terrain `source`
+    fn errors(source: &str) -> Vec<(String, Option<usize>, String)> {
+        let terrain: Terrain = serde_yaml::from_str(source).unwrap();
+        match validate(&terrain, source, "") {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors
+                .0
+                .into_iter()
+                .map(|error| (error.path, error.line, error.message))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn resources_terrain_is_valid() {
+        assert!(errors(include_str!("../../resources/terrain.yaml")).is_empty());
+    }
+
+    #[test]
+    fn unknown_references_are_reported() {
+        let source = "
+height:
+  add:
+    - constant: 1
+    - ref: missing
+";
+        assert_eq!(
+            errors(source),
+            vec![(
+                "height.add[1].ref".to_string(),
+                Some(5),
+                "refers to the unknown definition 'missing'".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let source = "
+definitions:
+  a: { negate: { ref: b } }
+  b: { ref: a }
+  c: { ref: c }
+  d: { ref: a }
+height: { ref: d }
+";
+        let paths: Vec<String> = errors(source).into_iter().map(|error| error.0).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "definitions.a.negate.ref",
+                "definitions.b.ref",
+                "definitions.c.ref"
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_are_reported() {
+        let source = "
+height:
+  add:
+    - noiseFBM: { frequency: 0, persistence: 0.5, octaves: 0 }
+    - clamp: { min: 1, max: -1, value: { constant: 0 } }
+    - pow: { exponent: -1, value: { noiseSimplex: {} } }
+    - terrace: { steps: [0.5, 0.1], value: { constant: 0 } }
+    - craters: { min_radius: 0.01, max_radius: 0.1, exponent: 3, density: 1, depth: 1 }
+    - noiseCellular: { distance_fn: Euclidean, return_type: Distance, jitter: .nan }
+";
+        let paths: Vec<(String, Option<usize>)> = errors(source)
+            .into_iter()
+            .map(|error| (error.0, error.1))
+            .collect();
+        let expected = vec![
+            ("height.add[0].noiseFBM.frequency", 4),
+            ("height.add[0].noiseFBM.octaves", 4),
+            ("height.add[1].clamp.min", 5),
+            ("height.add[2].pow.exponent", 6),
+            ("height.add[3].terrace.steps", 7),
+            ("height.add[4].craters.exponent", 8),
+            ("height.add[5].noiseCellular.jitter", 9),
+        ];
+        let expected: Vec<(String, Option<usize>)> = expected
+            .into_iter()
+            .map(|(path, line)| (path.to_string(), Some(line)))
+            .collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn missing_nodes_report_the_line_of_their_parent() {
+        let source = "
+height:
+  add:
+    - constant: 1
+    - craters: {
+        min_radius: 0.1, max_radius: 0.2,
+        density: 1, depth: 1
+      }
+";
+        let lines = line_numbers(source, "");
+        assert_eq!(line_of(&lines, "height.add[0].constant"), Some(4));
+        assert_eq!(line_of(&lines, "height.add[1].craters.depth"), Some(7));
+        assert_eq!(line_of(&lines, "height.add[1].craters.exponent"), Some(5));
+        assert_eq!(line_of(&lines, "colors.stops[0]"), None);
+    }
+}